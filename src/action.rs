@@ -1,9 +1,15 @@
-use crate::{RelativePos, Direction, Mode};
-use keymap::KeyMap;
-use termion::event::Key;
-use serde::{Serialize, Deserialize};
+use crate::keys::{self, Leaders};
+use crate::{Direction, Key, Mode, RelativePos};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use toml::Spanned;
 
-#[derive(Debug, Deserialize, PartialEq)]
+// The key map used when no config file says otherwise
+const DEFAULT_KEYMAP: &str = include_str!("../qwerty.toml");
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub enum Action {
     Composite(Vec<Action>),
     DeleteChar(Direction),
@@ -19,29 +25,376 @@ pub enum Action {
     MoveCursorHome,
     MoveCursorEnd,
     Search,
+    NextTab,
+    PreviousTab,
     Quit,
     Exit,
     Save,
+    ForceSave,
     None,
 }
 
-#[derive(Debug, Deserialize)]
+// What a key sequence is mapped to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mapping {
+    Actions(Vec<Action>),
+    // Keys handled as if they were typed; unless `remap` is set they skip the
+    // key map, like Vim's :noremap
+    Keys { keys: Vec<Key>, remap: bool },
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // Like a :noremap, since actions are never looked up again
+            Self::Actions(actions) => write!(f, "* {:?}", actions),
+            Self::Keys { keys, remap } => {
+                let flag = if *remap { ' ' } else { '*' };
+                write!(f, "{} {}", flag, keys::notation(keys))
+            }
+        }
+    }
+}
+
+// `keys` is a sequence, typed one after the other
+#[derive(Clone, Debug)]
 struct KeyAction {
-    keys: Vec<KeyMap>,
-    actions: Vec<Action>,
+    keys: Vec<Key>,
+    mapping: Mapping,
+    // Where the entry is in its file
+    span: Range<usize>,
 }
 
-#[derive(Debug, Deserialize)]
+// A config file only lists the modes it changes. `commands` are the Ex
+// commands defined under `[commands]`, by name.
+#[derive(Clone, Debug, Default)]
 pub struct KeyMapConfig {
     normal: Vec<KeyAction>,
     insert: Vec<KeyAction>,
     visual: Vec<KeyAction>,
     command: Vec<KeyAction>,
+    commands: BTreeMap<String, String>,
+}
+
+pub enum KeyMatch<'a> {
+    None,
+    // The keys start a longer sequence, which may be all that `exact` holds
+    Prefix { exact: Option<&'a Mapping> },
+    Exact(&'a Mapping),
+}
+
+// A problem with a key map entry, which starts at byte `span.start` of its
+// file. Warnings are about entries that work, though maybe not as meant.
+pub struct Problem {
+    pub span: Range<usize>,
+    pub message: String,
+    pub warning: bool,
+}
+
+// The entries as written, so that each key and action can be checked on its own
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RawKeyMap {
+    normal: Vec<Spanned<RawEntry>>,
+    insert: Vec<Spanned<RawEntry>>,
+    visual: Vec<Spanned<RawEntry>>,
+    command: Vec<Spanned<RawEntry>>,
+    commands: BTreeMap<Spanned<String>, String>,
+}
+
+// An entry has either `actions` or `send`, the keys it is mapped to
+#[derive(Deserialize)]
+struct RawEntry {
+    #[serde(default)]
+    keys: Option<Spanned<Vec<Spanned<String>>>>,
+    #[serde(default)]
+    actions: Option<Spanned<Vec<Spanned<toml::Value>>>>,
+    #[serde(default)]
+    send: Option<Spanned<String>>,
+    #[serde(default)]
+    remap: Option<Spanned<bool>>,
+}
+
+const MODES: [Mode; 4] = [Mode::Normal, Mode::Insert, Mode::Visual, Mode::Command];
+
+impl KeyMapConfig {
+    pub fn built_in() -> Self {
+        let (keymap, _) = Self::parse(DEFAULT_KEYMAP, &Leaders::default())
+            .expect("the built-in key map is valid");
+        keymap
+    }
+
+    // Reads a key map, leaving out the entries that have problems
+    pub fn parse(text: &str, leaders: &Leaders) -> Result<(Self, Vec<Problem>), toml::de::Error> {
+        let raw: RawKeyMap = toml::from_str(text)?;
+        let mut keymap = Self::default();
+        let mut problems = Vec::new();
+        let modes = [raw.normal, raw.insert, raw.visual, raw.command];
+        for (mode, entries) in MODES.into_iter().zip(modes) {
+            for entry in entries {
+                if let Some(entry) = read_entry(entry, leaders, &mut problems) {
+                    keymap.add(mode, entry, &mut problems);
+                }
+            }
+        }
+        for (name, command) in raw.commands {
+            match check_command_name(name.get_ref()) {
+                Ok(()) => {
+                    keymap.commands.insert(name.into_inner(), command);
+                }
+                Err(message) => problems.push(Problem {
+                    span: name.span(),
+                    message,
+                    warning: false,
+                }),
+            }
+        }
+        Ok((keymap, problems))
+    }
+
+    // A sequence mapped twice is an error, since only the first is used. One
+    // that starts another makes the shorter one wait for more keys.
+    fn add(&mut self, mode: Mode, entry: KeyAction, problems: &mut Vec<Problem>) {
+        let entries = self.entries_mut(mode);
+        if entries.iter().any(|existing| existing.keys == entry.keys) {
+            problems.push(Problem {
+                span: entry.span,
+                message: format!(
+                    "{} is already mapped in {} mode",
+                    keys::names(&entry.keys),
+                    mode.name()
+                ),
+                warning: false,
+            });
+            return;
+        }
+        if let Some(problem) = entries
+            .iter()
+            .find_map(|other| overlap(mode, &entry, other))
+        {
+            problems.push(problem);
+        }
+        entries.push(entry);
+    }
+
+    // Warns about the entries that make entries of `base`, which they are merged
+    // over, wait for more keys or that wait for them
+    pub fn overlaps(&self, base: &KeyMapConfig) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        for mode in MODES {
+            let mine = self.entries(mode);
+            for entry in mine {
+                let replaced = |other: &&KeyAction| mine.iter().any(|e| e.keys == other.keys);
+                let problem = base
+                    .entries(mode)
+                    .iter()
+                    .filter(|other| !replaced(other))
+                    .find_map(|other| overlap(mode, entry, other));
+                problems.extend(problem);
+            }
+        }
+        problems
+    }
+
+    // Entries of `other` replace those for the same keys in the same mode, and
+    // its commands those of the same name
+    pub fn merge(&mut self, other: KeyMapConfig) {
+        self.commands.extend(other.commands);
+        for (mine, theirs) in [
+            (&mut self.normal, other.normal),
+            (&mut self.insert, other.insert),
+            (&mut self.visual, other.visual),
+            (&mut self.command, other.command),
+        ] {
+            for entry in theirs {
+                mine.retain(|existing| existing.keys != entry.keys);
+                mine.push(entry);
+            }
+        }
+    }
+
+    fn entries(&self, mode: Mode) -> &[KeyAction] {
+        match mode {
+            Mode::Normal => &self.normal,
+            Mode::Insert => &self.insert,
+            Mode::Visual => &self.visual,
+            Mode::Command => &self.command,
+        }
+    }
+
+    fn entries_mut(&mut self, mode: Mode) -> &mut Vec<KeyAction> {
+        match mode {
+            Mode::Normal => &mut self.normal,
+            Mode::Insert => &mut self.insert,
+            Mode::Visual => &mut self.visual,
+            Mode::Command => &mut self.command,
+        }
+    }
+
+    // Maps `keys` in `mode`, replacing what they were mapped to
+    pub fn map(&mut self, mode: Mode, keys: Vec<Key>, mapping: Mapping) {
+        let entries = self.entries_mut(mode);
+        entries.retain(|entry| entry.keys != keys);
+        entries.push(KeyAction {
+            keys,
+            mapping,
+            span: 0..0,
+        });
+    }
+
+    // Returns false when `keys` were not mapped in `mode`
+    pub fn unmap(&mut self, mode: Mode, keys: &[Key]) -> bool {
+        let entries = self.entries_mut(mode);
+        let len = entries.len();
+        entries.retain(|entry| entry.keys != keys);
+        entries.len() < len
+    }
+
+    pub fn mappings(&self, mode: Mode) -> impl Iterator<Item = (&[Key], &Mapping)> {
+        self.entries(mode)
+            .iter()
+            .map(|entry| (entry.keys.as_slice(), &entry.mapping))
+    }
+
+    pub fn command(&self, name: &str) -> Option<&str> {
+        self.commands.get(name).map(String::as_str)
+    }
+
+    pub fn commands(&self) -> &BTreeMap<String, String> {
+        &self.commands
+    }
+
+    pub fn define_command(&mut self, name: &str, command: &str) -> Result<(), String> {
+        check_command_name(name)?;
+        self.commands.insert(name.to_string(), command.to_string());
+        Ok(())
+    }
+
+    // Returns false when there was no such command
+    pub fn delete_command(&mut self, name: &str) -> bool {
+        self.commands.remove(name).is_some()
+    }
+
+    pub fn lookup(&self, mode: Mode, keys: &[Key]) -> KeyMatch<'_> {
+        let entries = self.entries(mode);
+        let exact = entries
+            .iter()
+            .find(|entry| entry.keys == keys)
+            .map(|entry| &entry.mapping);
+        let longer = entries
+            .iter()
+            .any(|entry| entry.keys.len() > keys.len() && entry.keys.starts_with(keys));
+        match exact {
+            _ if longer => KeyMatch::Prefix { exact },
+            Some(mapping) => KeyMatch::Exact(mapping),
+            None => KeyMatch::None,
+        }
+    }
 }
 
+// Like Vim, user commands start with a capital so they never clash with the
+// built-in ones
+fn check_command_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_uppercase() && chars.all(|c| c.is_ascii_alphanumeric()) => Ok(()),
+        _ => Err(format!(
+            "Command name must start with a capital letter: {}",
+            name
+        )),
+    }
+}
 
-impl From<&str> for KeyMapConfig {
-    fn from(config: &str) -> Self {
-        toml::from_str(config).unwrap()
+// Checks every key and action of an entry, keeping it only if all are valid
+fn read_entry(
+    entry: Spanned<RawEntry>,
+    leaders: &Leaders,
+    problems: &mut Vec<Problem>,
+) -> Option<KeyAction> {
+    let span = entry.span();
+    let entry = entry.into_inner();
+    let start = problems.len();
+    let mut error = |span: Range<usize>, message: String| {
+        problems.push(Problem {
+            span,
+            message,
+            warning: false,
+        })
+    };
+    let mut keys = Vec::new();
+    match entry.keys {
+        None => error(span.clone(), "missing field `keys`".to_string()),
+        Some(names) if names.get_ref().is_empty() => {
+            error(names.span(), "no keys given".to_string())
+        }
+        Some(names) => {
+            for name in names.into_inner() {
+                match leaders.key(name.get_ref()) {
+                    Some(key) => keys.push(key),
+                    None => error(name.span(), format!("unknown key: {}", name.get_ref())),
+                }
+            }
+        }
+    }
+    if let (Some(remap), None) = (&entry.remap, &entry.send) {
+        error(remap.span(), "`remap` only goes with `send`".to_string());
+    }
+    let mapping = match (entry.actions, entry.send) {
+        (Some(_), Some(send)) => {
+            error(
+                send.span(),
+                "`actions` and `send` cannot both be given".to_string(),
+            );
+            None
+        }
+        (None, None) => {
+            error(
+                span.clone(),
+                "missing field `actions` or `send`".to_string(),
+            );
+            None
+        }
+        (None, Some(send)) => Some(Mapping::Keys {
+            keys: leaders.parse(send.get_ref()),
+            remap: entry.remap.is_some_and(|remap| *remap.get_ref()),
+        }),
+        (Some(values), None) => {
+            let mut actions = Vec::new();
+            for value in values.into_inner() {
+                let span = value.span();
+                match value.into_inner().try_into::<Action>() {
+                    Ok(action) => actions.push(action),
+                    Err(e) => error(span, e.message().to_string()),
+                }
+            }
+            Some(Mapping::Actions(actions))
+        }
+    };
+    match mapping {
+        Some(mapping) if problems.len() == start => Some(KeyAction {
+            keys,
+            mapping,
+            span,
+        }),
+        _ => None,
     }
-}
\ No newline at end of file
+}
+
+// A warning when one of the two sequences starts the other
+fn overlap(mode: Mode, entry: &KeyAction, other: &KeyAction) -> Option<Problem> {
+    let (shorter, longer) = if entry.keys.len() < other.keys.len() {
+        (&entry.keys, &other.keys)
+    } else {
+        (&other.keys, &entry.keys)
+    };
+    (shorter.len() < longer.len() && longer.starts_with(shorter)).then(|| Problem {
+        span: entry.span.clone(),
+        message: format!(
+            "{} waits for more keys in {} mode, since {} is mapped too",
+            keys::names(shorter),
+            mode.name(),
+            keys::names(longer)
+        ),
+        warning: true,
+    })
+}
@@ -0,0 +1,132 @@
+use crate::{keys, Frame, Key, Size};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+pub enum Event {
+    Key(Key),
+    Resize(Size),
+    FocusGained,
+}
+
+// Makes a backend's `read_event` return early, from any thread
+#[derive(Clone)]
+pub struct Waker(Arc<dyn Fn() + Send + Sync>);
+
+impl Waker {
+    pub fn new<F>(wake: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Self(Arc::new(wake))
+    }
+
+    pub fn wake(&self) {
+        (self.0)()
+    }
+}
+
+// Where the editor's frames go and its input comes from
+pub trait Backend {
+    fn size(&self) -> Size;
+
+    fn draw(&mut self, frame: &Frame) -> Result<(), io::Error>;
+
+    // Waits up to `timeout`, or for as long as it takes when there is none.
+    // Returns None when the timeout passed or a waker was woken, and an
+    // `UnexpectedEof` error once the input has ended.
+    fn read_event(&mut self, timeout: Option<Duration>) -> Result<Option<Event>, io::Error>;
+
+    fn waker(&self) -> Waker;
+}
+
+// A virtual screen fed from a queue of events, for driving the editor without a tty
+pub struct MemoryBackend {
+    screen: Frame,
+    events: VecDeque<Event>,
+    // Set by the wakers until `read_event` next finds the queue empty
+    woken: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl MemoryBackend {
+    pub fn new(size: Size) -> Self {
+        Self {
+            screen: Frame::new(size),
+            events: VecDeque::new(),
+            woken: Arc::default(),
+        }
+    }
+
+    pub fn push_event(&mut self, event: Event) {
+        self.events.push_back(event);
+    }
+
+    // Queues keys written in Vim notation, e.g. `ihello<Esc>:w<CR>`
+    pub fn push_keys(&mut self, notation: &str) {
+        self.events
+            .extend(keys::parse(notation).into_iter().map(Event::Key));
+    }
+
+    // Waits up to `timeout` for a waker to be woken, e.g. by a job with output
+    // for the editor to read. Returns whether one was.
+    pub fn wait_for_wake(&self, timeout: Duration) -> bool {
+        let (woken, condvar) = &*self.woken;
+        let woken = woken.lock().unwrap();
+        let (woken, _) = condvar
+            .wait_timeout_while(woken, timeout, |woken| !*woken)
+            .unwrap();
+        *woken
+    }
+
+    // The last frame drawn
+    pub fn screen(&self) -> &Frame {
+        &self.screen
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        (0..self.screen.height())
+            .map(|y| self.screen.line(y))
+            .collect()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn size(&self) -> Size {
+        Size {
+            width: self.screen.width() as u16,
+            height: self.screen.height() as u16,
+        }
+    }
+
+    fn draw(&mut self, frame: &Frame) -> Result<(), io::Error> {
+        self.screen = frame.clone();
+        Ok(())
+    }
+
+    // Nothing arrives while waiting, so the queued events are all there is. A
+    // wake once they ran out returns early, so the editor reads what woke it.
+    fn read_event(&mut self, _timeout: Option<Duration>) -> Result<Option<Event>, io::Error> {
+        let Some(event) = self.events.pop_front() else {
+            let woken = std::mem::take(&mut *self.woken.0.lock().unwrap());
+            return if woken {
+                Ok(None)
+            } else {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            };
+        };
+        if let Event::Resize(size) = &event {
+            self.screen = Frame::new(*size);
+        }
+        Ok(Some(event))
+    }
+
+    fn waker(&self) -> Waker {
+        let woken = Arc::clone(&self.woken);
+        Waker::new(move || {
+            let (flag, condvar) = &*woken;
+            *flag.lock().unwrap() = true;
+            condvar.notify_all();
+        })
+    }
+}
@@ -0,0 +1,31 @@
+use crate::options::Options;
+use crate::{Document, Pos};
+
+pub struct Buffer {
+    pub id: usize,
+    pub document: Document,
+    // Where the cursor and viewport were when the buffer was last shown
+    pub cursor_pos: Pos,
+    pub offset: Pos,
+    // Values set with :setlocal, e.g. tabstop
+    pub options: Options,
+}
+
+impl Buffer {
+    pub fn new(id: usize, document: Document) -> Self {
+        Self {
+            id,
+            document,
+            cursor_pos: Pos::default(),
+            offset: Pos::default(),
+            options: Options::default(),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.document
+            .file_name
+            .clone()
+            .unwrap_or("[No Name]".to_string())
+    }
+}
@@ -0,0 +1,150 @@
+use crate::window::Split;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "Usage: rustvim [options] [file ...]
+
+Options:
+  +                    Start at the end of the first file
+  +N                   Start at line N of the first file
+  +/pattern            Start at the first match of pattern
+  -c command           Run an Ex command after loading the files (repeatable)
+  -o[N]                Open the files in horizontal splits (at most N)
+  -O[N]                Open the files in vertical splits (at most N)
+  -es, --headless      Run the -c commands, then Ex commands from stdin,
+                       without a user interface
+  -R                   Open the files read-only
+  -r [file|swapfile]   Recover from a swap file; without a file, list swap files
+  -                    Read the text from standard input
+  --clean              Skip the configuration files
+  --check-config [file ...]
+                       Report the problems in the configuration files, or in
+                       the given files, and exit
+  -h, --help           Print this help and exit
+  -v, --version        Print the version and exit
+
+A file may be given as file:line or file:line:column.";
+
+pub struct FileArg {
+    pub name: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl FileArg {
+    // Splits a trailing `:line` or `:line:column`, as printed by compilers,
+    // unless a file with the full name exists
+    fn parse(arg: &str) -> Self {
+        let mut file = Self {
+            name: arg.to_string(),
+            line: None,
+            column: None,
+        };
+        if Path::new(arg).exists() {
+            return file;
+        }
+        let mut name = arg.strip_suffix(':').unwrap_or(arg);
+        let mut numbers = Vec::new();
+        while numbers.len() < 2 {
+            let Some((rest, number)) = name.rsplit_once(':') else {
+                break;
+            };
+            match number.parse::<usize>() {
+                Ok(number) if !rest.is_empty() => {
+                    numbers.insert(0, number);
+                    name = rest;
+                }
+                _ => break,
+            }
+        }
+        if let Some(line) = numbers.first() {
+            file.name = name.to_string();
+            file.line = Some(*line);
+            file.column = numbers.get(1).copied();
+        }
+        file
+    }
+}
+
+#[derive(Default)]
+pub struct Args {
+    pub files: Vec<FileArg>,
+    // Ex commands from `+cmd` and `-c cmd`, in the order they were given
+    pub commands: Vec<String>,
+    pub split: Option<Split>,
+    pub max_windows: Option<usize>,
+    pub read_only: bool,
+    pub recover: bool,
+    pub stdin: bool,
+    pub clean: bool,
+    pub headless: bool,
+}
+
+pub enum Invocation {
+    Edit(Args),
+    // The files to check; none means the ones read at startup
+    CheckConfig(Vec<PathBuf>),
+    Help,
+    Version,
+}
+
+pub fn parse<I>(args: I) -> Result<Invocation, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+    // Like Vim, `view` is the editor started with -R
+    let view = args
+        .next()
+        .as_deref()
+        .and_then(|program| Path::new(program).file_name())
+        .is_some_and(|name| name == "view");
+    let mut parsed = Args {
+        read_only: view,
+        ..Args::default()
+    };
+    let mut only_files = false;
+    while let Some(arg) = args.next() {
+        if only_files {
+            parsed.files.push(FileArg::parse(&arg));
+            continue;
+        }
+        match arg.as_str() {
+            "--" => only_files = true,
+            "-h" | "--help" => return Ok(Invocation::Help),
+            "-v" | "--version" => return Ok(Invocation::Version),
+            "--clean" => parsed.clean = true,
+            "--check-config" => {
+                return Ok(Invocation::CheckConfig(args.map(PathBuf::from).collect()))
+            }
+            "-es" | "--headless" => parsed.headless = true,
+            "-R" => parsed.read_only = true,
+            "-r" => parsed.recover = true,
+            "-" => parsed.stdin = true,
+            "-c" => {
+                let command = args
+                    .next()
+                    .ok_or_else(|| "argument missing after -c".to_string())?;
+                parsed.commands.push(command);
+            }
+            "+" => parsed.commands.push("$".to_string()),
+            _ if arg.starts_with('+') => parsed.commands.push(arg[1..].to_string()),
+            _ if arg.starts_with("-o") || arg.starts_with("-O") => {
+                parsed.split = Some(if arg.starts_with("-o") {
+                    Split::Horizontal
+                } else {
+                    Split::Vertical
+                });
+                let count = &arg[2..];
+                if !count.is_empty() {
+                    let count = count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid window count: {}", arg))?;
+                    parsed.max_windows = Some(count);
+                }
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+            _ => parsed.files.push(FileArg::parse(&arg)),
+        }
+    }
+    Ok(Invocation::Edit(parsed))
+}
@@ -0,0 +1,260 @@
+use crate::action::Problem;
+use crate::error::{Context, Error, Result};
+use crate::options::{self, OptionDef, Options, Value};
+use crate::KeyMapConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const CONFIG_FILE: &str = "config.toml";
+// Read from the working directory once `:trust` was used on it, since it may
+// come with a cloned repository
+pub const PROJECT_FILE: &str = ".rustvim.toml";
+const TRUST_FILE: &str = "trust";
+
+// What one config file sets: key map entries per mode, and option values under
+// `[options]`
+pub struct ConfigFile {
+    pub keymap: KeyMapConfig,
+    pub options: Vec<(&'static OptionDef, Value)>,
+}
+
+// A problem found in a config file; files with only warnings are used as they are
+pub struct Diagnostic {
+    pub error: Error,
+    pub warning: bool,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct OptionsTable {
+    options: BTreeMap<String, toml::Spanned<toml::Value>>,
+}
+
+// Reads the config file at `path`, or None when there is no such file. Entries
+// and options with problems are left out and the problems returned with the
+// rest. Only a file that is not valid TOML, or not laid out like a config file,
+// fails as a whole. `options` are those set before, for the leader keys.
+pub fn load(path: &Path, options: &Options) -> Result<Option<(ConfigFile, Vec<Diagnostic>)>> {
+    read(path)?
+        .map(|text| parse(path, &text, options))
+        .transpose()
+}
+
+// Every problem in `files`, which are read in order, each merged over the
+// built-in key map and the ones before it
+pub fn check(files: &[PathBuf]) -> Vec<Diagnostic> {
+    let mut keymap = KeyMapConfig::built_in();
+    let mut options = Options::default();
+    let mut diagnostics = Vec::new();
+    for path in files {
+        let parsed = fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))
+            .and_then(|text| parse(path, &text, &options).map(|parsed| (text, parsed)));
+        match parsed {
+            Ok((text, (config, problems))) => {
+                let start = diagnostics.len();
+                diagnostics.extend(problems);
+                for problem in config.keymap.overlaps(&keymap) {
+                    diagnostics.push(diagnostic(path, &text, problem));
+                }
+                sort(&mut diagnostics[start..]);
+                keymap.merge(config.keymap);
+                for (option, value) in config.options {
+                    options.set(option, value);
+                }
+            }
+            Err(error) => diagnostics.push(Diagnostic {
+                error,
+                warning: false,
+            }),
+        }
+    }
+    diagnostics
+}
+
+fn read(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(Some(text)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error).with_context(|| format!("Could not read {}", path.display())),
+    }
+}
+
+// The options come first, since the leader keys they set are used by the
+// mappings of the same file
+fn parse(path: &Path, text: &str, before: &Options) -> Result<(ConfigFile, Vec<Diagnostic>)> {
+    let error = |e: toml::de::Error| Error::config(path.to_path_buf(), text, &e);
+    let table: OptionsTable = toml::from_str(text).map_err(error)?;
+    let mut diagnostics = Vec::new();
+    let mut options = Vec::new();
+    for (name, value) in table.options {
+        let option = match options::find(&name) {
+            Some(option) if options::is_per_document(option) => {
+                Err(format!("{} can only be set with :set", option.name))
+            }
+            Some(option) => option.parse_toml(value.get_ref()).map(|v| (option, v)),
+            None => Err(format!("Unknown option: {}", name)),
+        };
+        match option {
+            Ok(option) => options.push(option),
+            Err(message) => diagnostics.push(diagnostic(
+                path,
+                text,
+                Problem {
+                    span: value.span(),
+                    message,
+                    warning: false,
+                },
+            )),
+        }
+    }
+    let mut leaders = before.clone();
+    for (option, value) in &options {
+        leaders.set(option, value.clone());
+    }
+    let (keymap, problems) = KeyMapConfig::parse(text, &leaders.leaders()).map_err(error)?;
+    diagnostics.extend(
+        problems
+            .into_iter()
+            .map(|problem| diagnostic(path, text, problem)),
+    );
+    sort(&mut diagnostics);
+    Ok((ConfigFile { keymap, options }, diagnostics))
+}
+
+// In the order they are in their file
+fn sort(diagnostics: &mut [Diagnostic]) {
+    diagnostics.sort_by_key(|d| match d.error {
+        Error::Config { position, .. } => position,
+        _ => None,
+    });
+}
+
+fn diagnostic(path: &Path, text: &str, problem: Problem) -> Diagnostic {
+    let offset = Some(problem.span.start);
+    Diagnostic {
+        error: Error::config_at(path.to_path_buf(), text, offset, &problem.message),
+        warning: problem.warning,
+    }
+}
+
+// A config file that was read, to read it again when it changes
+pub struct Source {
+    pub path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl Source {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified(&path);
+        Self { path, modified }
+    }
+
+    // Whether the file was written, created or removed since it was last read
+    pub fn changed(&self) -> bool {
+        modified(&self.path) != self.modified
+    }
+
+    pub fn refresh(&mut self) {
+        self.modified = modified(&self.path);
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// $XDG_CONFIG_HOME/rustvim, where the user's config.toml is
+pub fn config_dir() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config").join("rustvim")
+}
+
+// $XDG_STATE_HOME/rustvim, for swap files and the trusted project files
+pub fn state_dir() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", ".local/state").join("rustvim")
+}
+
+fn xdg_dir(variable: &str, default: &str) -> PathBuf {
+    env::var_os(variable)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(default)))
+        .unwrap_or_else(env::temp_dir)
+}
+
+// The files read at startup, later ones overriding earlier ones. The user's file
+// is watched even before it exists.
+pub fn startup_files() -> Vec<PathBuf> {
+    let mut files = vec![config_dir().join(CONFIG_FILE)];
+    let project = Path::new(PROJECT_FILE);
+    if project.exists() && is_trusted(project) {
+        files.push(project.to_path_buf());
+    }
+    files
+}
+
+// The files `--check-config` looks at: the user's file and the project file,
+// trusted or not, when they exist
+pub fn existing_files() -> Vec<PathBuf> {
+    [config_dir().join(CONFIG_FILE), PathBuf::from(PROJECT_FILE)]
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect()
+}
+
+pub fn is_trusted(path: &Path) -> bool {
+    let (Some(entry), Ok(trusted)) = (trust_entry(path), fs::read_to_string(trust_file())) else {
+        return false;
+    };
+    trusted.lines().any(|line| line == entry)
+}
+
+// Trusts `path` as it is now; once it is changed it has to be trusted again
+pub fn trust(path: &Path) -> Result<()> {
+    let entry = trust_entry(path)
+        .ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    let file = trust_file();
+    let full = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let suffix = format!(" {}", full.display());
+    let mut lines: Vec<String> = match fs::read_to_string(&file) {
+        Ok(trusted) => trusted
+            .lines()
+            .filter(|line| !line.ends_with(&suffix))
+            .map(str::to_string)
+            .collect(),
+        Err(error) if error.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Could not read {}", file.display()))
+        }
+    };
+    lines.push(entry);
+    let write = |file: &Path| {
+        fs::create_dir_all(file.parent().unwrap_or(Path::new(".")))?;
+        fs::write(file, lines.join("\n") + "\n")
+    };
+    write(&file).with_context(|| format!("Could not write {}", file.display()))
+}
+
+fn trust_file() -> PathBuf {
+    state_dir().join(TRUST_FILE)
+}
+
+// The file's contents hash and full path
+fn trust_entry(path: &Path) -> Option<String> {
+    let contents = fs::read(path).ok()?;
+    let full = fs::canonicalize(path).ok()?;
+    Some(format!("{:016x} {}", hash(&contents), full.display()))
+}
+
+// FNV-1a, which unlike std's hasher is the same in every build
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
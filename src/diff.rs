@@ -0,0 +1,99 @@
+const CONTEXT: usize = 2;
+// Above this many cells the middle of the files is shown as one replaced block
+const MAX_TABLE_SIZE: usize = 4_000_000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+// A line based diff of `old` against `new` in unified style
+pub fn unified(old: &[&str], new: &[&str]) -> Vec<String> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut ops = vec![Op::Same; prefix];
+    ops.extend(middle(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    ));
+    ops.extend(vec![Op::Same; suffix]);
+    render(&ops, old, new)
+}
+
+fn middle(old: &[&str], new: &[&str]) -> Vec<Op> {
+    if old.len().saturating_mul(new.len()) > MAX_TABLE_SIZE {
+        let mut ops = vec![Op::Removed; old.len()];
+        ops.extend(vec![Op::Added; new.len()]);
+        return ops;
+    }
+    // lengths[i][j] is the longest common subsequence of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut ops = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push(Op::Same);
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            ops.push(Op::Removed);
+            i += 1;
+        } else {
+            ops.push(Op::Added);
+            j += 1;
+        }
+    }
+    ops
+}
+
+fn render(ops: &[Op], old: &[&str], new: &[&str]) -> Vec<String> {
+    let mut shown = vec![false; ops.len()];
+    for (index, op) in ops.iter().enumerate() {
+        if *op != Op::Same {
+            let end = (index + CONTEXT + 1).min(ops.len());
+            shown[index.saturating_sub(CONTEXT)..end].fill(true);
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut old_line, mut new_line) = (0, 0);
+    let mut last_shown = None;
+    for (index, op) in ops.iter().enumerate() {
+        if shown[index] {
+            if last_shown.map_or(true, |last| last + 1 != index) {
+                lines.push(format!("@@ -{} +{} @@", old_line + 1, new_line + 1));
+            }
+            last_shown = Some(index);
+            lines.push(match op {
+                Op::Same => format!("  {}", old[old_line]),
+                Op::Removed => format!("- {}", old[old_line]),
+                Op::Added => format!("+ {}", new[new_line]),
+            });
+        }
+        match op {
+            Op::Same => {
+                old_line += 1;
+                new_line += 1;
+            }
+            Op::Removed => old_line += 1,
+            Op::Added => new_line += 1,
+        }
+    }
+    lines
+}
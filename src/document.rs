@@ -1,24 +1,203 @@
-use crate::{Direction, FileType, Pos, Row};
+use crate::hex::{self, HexBuffer};
+use crate::loader::{Chunk, Loader, LAZY_LOAD_THRESHOLD};
+use crate::swap::{SwapFile, SwapInfo};
+use crate::writer;
+use crate::{Direction, Encoding, FileFormat, FileType, Pos, Row};
 use std::fs;
-use std::io::{Error, Write};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+// What the file looked like on disk when it was last read or written
+#[derive(Clone, Copy, PartialEq)]
+struct DiskState {
+    modified: Option<SystemTime>,
+    size: u64,
+    inode: u64,
+}
+
+impl From<&fs::Metadata> for DiskState {
+    fn from(metadata: &fs::Metadata) -> Self {
+        Self {
+            modified: metadata.modified().ok(),
+            size: metadata.len(),
+            inode: metadata.ino(),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct Document {
     pub file_name: Option<String>,
     file_type: FileType,
+    file_format: FileFormat,
+    encoding: Encoding,
+    no_eol: bool,
     rows: Vec<Row>,
     dirty: bool,
+    changes: usize,
+    read_only: bool,
+    swap: Option<SwapFile>,
+    disk_state: Option<DiskState>,
+    loader: Option<Loader>,
+    load_error: Option<String>,
+    hex: Option<HexBuffer>,
 }
 
 impl Document {
-    pub fn open(file_name: &str) -> Result<Self, std::io::Error> {
-        let contents = fs::read_to_string(file_name)?;
-        Ok(Self {
-            rows: contents.lines().map(Row::from).collect(),
-            dirty: false,
+    // An empty document for a file that does not exist yet
+    pub fn new(file_name: &str) -> Self {
+        Self {
             file_name: Some(file_name.to_string()),
             file_type: FileType::from(file_name),
-        })
+            ..Self::default()
+        }
+    }
+
+    pub fn open(file_name: &str) -> Result<Self, std::io::Error> {
+        let file = fs::File::open(file_name)?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        let mut document = Self {
+            disk_state: Some(DiskState::from(&metadata)),
+            read_only: !writer::is_writable(Path::new(file_name)),
+            ..Self::new(file_name)
+        };
+        let mut reader = BufReader::new(file);
+        document.encoding = Encoding::detect(reader.fill_buf()?);
+        let byte_oriented = document.encoding.is_byte_oriented();
+        if size < LAZY_LOAD_THRESHOLD || !byte_oriented || hex::is_binary(reader.fill_buf()?) {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            document.load_bytes(bytes)?;
+        } else {
+            reader.consume(document.encoding.bom().len());
+            document.file_format = FileFormat::detect(reader.fill_buf()?);
+            let mut loader = Loader::spawn(reader, size, document.file_format, document.encoding);
+            let chunk = loader.next()?;
+            document.loader = Some(loader);
+            document.append_chunk(Ok(chunk))?;
+        }
+        Ok(document)
+    }
+
+    // An unnamed document holding `bytes`, e.g. text piped to standard input
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, Error> {
+        let mut document = Self {
+            encoding: Encoding::detect(&bytes),
+            ..Self::default()
+        };
+        document.load_bytes(bytes)?;
+        Ok(document)
+    }
+
+    fn load_bytes(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
+        if self.encoding.is_byte_oriented() && hex::is_binary(&bytes) {
+            let buffer = HexBuffer::from(bytes);
+            self.rows = buffer.rows();
+            self.hex = Some(buffer);
+            self.file_type = FileType::default();
+        } else {
+            let contents = self.decode(&bytes)?;
+            self.file_format = FileFormat::detect(contents.as_bytes());
+            let (lines, eol) = self.file_format.split(&contents);
+            self.rows = lines.into_iter().map(Row::from).collect();
+            self.no_eol = !eol;
+        }
+        Ok(())
+    }
+
+    // Falls back to latin1 when the detected encoding turns out to be invalid
+    fn decode(&mut self, bytes: &[u8]) -> Result<String, Error> {
+        let encoding = Encoding::detect(bytes);
+        match encoding.decode(&bytes[encoding.bom().len()..]) {
+            Ok(contents) => {
+                self.encoding = encoding;
+                Ok(contents)
+            }
+            Err(_) => {
+                self.encoding = Encoding::Latin1;
+                Encoding::Latin1.decode(bytes)
+            }
+        }
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loader.is_some()
+    }
+
+    pub fn loading_progress(&self) -> Option<u8> {
+        self.loader.as_ref().map(Loader::progress)
+    }
+
+    // Why loading stopped early, until the file is reloaded
+    pub fn load_error(&self) -> Option<&str> {
+        self.load_error.as_deref()
+    }
+
+    // Appends the next chunk the background reader has indexed, if any, without blocking
+    pub fn poll_loading(&mut self) -> Result<(), Error> {
+        if let Some(chunk) = self.loader.as_mut().and_then(Loader::try_next) {
+            self.append_chunk(chunk)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish_loading(&mut self) -> Result<(), Error> {
+        while let Some(loader) = self.loader.as_mut() {
+            let chunk = loader.next();
+            self.append_chunk(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn append_chunk(&mut self, chunk: Result<Chunk, Error>) -> Result<(), Error> {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                self.loader = None;
+                self.load_error = Some(error.to_string());
+                return Err(error);
+            }
+        };
+        if chunk.bytes == 0 {
+            self.loader = None;
+        }
+        self.no_eol |= chunk.no_eol;
+        let start = self.rows.len();
+        self.rows.extend(chunk.rows);
+        if let Some(from) = chunk.unix_from {
+            self.fall_back_to_unix(start + from);
+        }
+        match chunk.latin1_from {
+            Some(from) => self.fall_back_to_latin1(start + from),
+            None => Ok(()),
+        }
+    }
+
+    // Gives the first `count` rows back the carriage return they lost when the
+    // loader read them as Dos lines, as splitting the whole file as Unix would
+    fn fall_back_to_unix(&mut self, count: usize) {
+        for row in &mut self.rows[..count] {
+            *row = Row::from(format!("{}\r", row.as_str()).as_str());
+        }
+        self.file_format = FileFormat::Unix;
+    }
+
+    // Decodes the first `count` rows again, the ones read before the loader fell
+    // back, like `decode` does for a whole file, so that saving writes the same bytes
+    fn fall_back_to_latin1(&mut self, count: usize) -> Result<(), Error> {
+        for row in &mut self.rows[..count] {
+            *row = Row::from(Encoding::Latin1.decode(row.as_str().as_bytes())?.as_str());
+        }
+        // Latin1 has no byte order mark, so it becomes text like in `decode`
+        let bom = Encoding::Latin1.decode(self.encoding.bom())?;
+        if let Some(first) = self.rows.first_mut().filter(|_| !bom.is_empty()) {
+            *first = Row::from(format!("{}{}", bom, first.as_str()).as_str());
+        }
+        self.encoding = Encoding::Latin1;
+        Ok(())
     }
 
     pub fn row(&self, index: usize) -> Option<&Row> {
@@ -37,15 +216,162 @@ impl Document {
         self.dirty
     }
 
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+        self.changes += 1;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Cannot make changes, buffer is read-only (use :set noreadonly)",
+            ));
+        }
+        Ok(())
+    }
+
+    // Writes the swap file once enough changes have piled up since the last write
+    pub fn update_swap(&mut self) -> Result<(), Error> {
+        if !self.dirty || self.read_only || self.is_loading() || self.is_binary() {
+            return Ok(());
+        }
+        let file_name = self.file_name.as_deref();
+        let swap = self
+            .swap
+            .get_or_insert_with(|| SwapFile::for_file(file_name));
+        if swap.is_due(self.changes) {
+            swap.write(file_name, self.rows.iter().map(Row::as_str), self.changes)?;
+        }
+        Ok(())
+    }
+
+    // When `update_swap` has something to write
+    pub fn swap_due_at(&self) -> Option<Instant> {
+        if !self.dirty || self.read_only || self.is_loading() || self.is_binary() {
+            return None;
+        }
+        match &self.swap {
+            Some(swap) => swap.due_at(self.changes),
+            None => Some(Instant::now()),
+        }
+    }
+
+    pub fn remove_swap(&mut self) {
+        if let Some(mut swap) = self.swap.take() {
+            swap.remove();
+        }
+    }
+
+    // Whether another program rewrote the file since it was opened or saved
+    pub fn changed_on_disk(&self) -> bool {
+        let (Some(file_name), Some(state)) = (&self.file_name, self.disk_state) else {
+            return false;
+        };
+        match fs::metadata(file_name) {
+            Ok(metadata) => DiskState::from(&metadata) != state,
+            Err(_) => false,
+        }
+    }
+
+    // Accepts the version on disk as the new baseline without reloading it
+    pub fn acknowledge_disk_change(&mut self) {
+        if let Some(file_name) = &self.file_name {
+            self.disk_state = fs::metadata(file_name).ok().map(|m| DiskState::from(&m));
+        }
+    }
+
+    pub fn reload(&mut self) -> Result<(), Error> {
+        let file_name = self
+            .file_name
+            .clone()
+            .ok_or_else(|| Error::new(std::io::ErrorKind::InvalidInput, "No file name"))?;
+        let mut document = Self::open(&file_name)?;
+        document.read_only = self.read_only;
+        self.remove_swap();
+        *self = document;
+        Ok(())
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.rows.iter().map(Row::as_str)
+    }
+
+    pub fn recover(&mut self, info: SwapInfo) {
+        self.rows = info
+            .rows
+            .iter()
+            .map(|row| Row::from(row.as_str()))
+            .collect();
+        self.loader = None;
+        self.mark_dirty();
+    }
+
     pub fn file_type(&self) -> String {
         self.file_type.name()
     }
 
-    pub fn insert(&mut self, at: &Pos, c: char) {
-        if at.y > self.len() {
-            return;
+    pub fn file_format(&self) -> FileFormat {
+        self.file_format
+    }
+
+    pub fn set_file_format(&mut self, format: FileFormat) {
+        if format != self.file_format {
+            self.file_format = format;
+            self.mark_dirty();
         }
-        self.dirty = true;
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        if encoding != self.encoding {
+            self.encoding = encoding;
+            self.mark_dirty();
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.hex.is_some()
+    }
+
+    // Returns the new cursor position, or `None` if `c` cannot be written there
+    pub fn overwrite_byte(&mut self, at: &Pos, c: char) -> Result<Option<Pos>, Error> {
+        self.check_writable()?;
+        let Some(hex) = self.hex.as_mut() else {
+            return Ok(None);
+        };
+        let Some(next) = hex.overwrite(at, c) else {
+            return Ok(None);
+        };
+        self.rows[at.y] = hex.row(at.y);
+        self.mark_dirty();
+        Ok(Some(next))
+    }
+
+    pub fn has_eol(&self) -> bool {
+        !self.no_eol
+    }
+
+    pub fn insert(&mut self, at: &Pos, c: char) -> Result<(), Error> {
+        self.check_writable()?;
+        if at.y > self.len() || self.is_binary() {
+            return Ok(());
+        }
+        if at.y == self.len() && self.is_loading() {
+            self.finish_loading()?;
+        }
+        self.mark_dirty();
         if c == '\n' {
             self.insert_newline(at);
         } else if at.y == self.rows.len() {
@@ -54,10 +380,11 @@ impl Document {
             self.rows[at.y].insert(at.x, c);
         }
         self.unhighlight_rows(at.y);
+        Ok(())
     }
 
-    pub fn insert_newline(&mut self, at: &Pos) {
-        if at.y > self.len() {
+    fn insert_newline(&mut self, at: &Pos) {
+        if at.y > self.len() || self.is_binary() {
             return;
         }
         if at.y == self.len() {
@@ -69,7 +396,13 @@ impl Document {
         self.rows.insert(at.y + 1, new_row);
     }
 
-    pub fn find(&self, query: &str, at: &Pos, direction: Direction) -> Option<Pos> {
+    pub fn find(
+        &self,
+        query: &str,
+        at: &Pos,
+        direction: Direction,
+        ignore_case: bool,
+    ) -> Option<Pos> {
         if at.y >= self.rows.len() {
             return None;
         }
@@ -88,7 +421,7 @@ impl Document {
 
         for _ in start..end {
             if let Some(row) = self.rows.get(pos.y) {
-                if let Some(x) = row.find(query, pos.x, direction) {
+                if let Some(x) = row.find(query, pos.x, direction, ignore_case) {
                     pos.x = x;
                     return Some(pos);
                 }
@@ -106,12 +439,13 @@ impl Document {
         None
     }
 
-    pub fn delete(&mut self, at: &Pos) {
+    pub fn delete(&mut self, at: &Pos) -> Result<(), Error> {
+        self.check_writable()?;
         let len = self.len();
-        if at.y >= len {
-            return;
+        if at.y >= len || self.is_binary() {
+            return Ok(());
         }
-        self.dirty = true;
+        self.mark_dirty();
         if at.x == self.rows[at.y].len() && at.y < len - 1 {
             let next_row = self.rows.remove(at.y + 1);
             let row = &mut self.rows[at.y];
@@ -121,24 +455,72 @@ impl Document {
             row.delete(at.x);
         }
         self.unhighlight_rows(at.y);
+        Ok(())
     }
 
-    pub fn save(&mut self) -> Result<(), Error> {
-        if self.file_name.is_none() {
+    // `force` writes even when the buffer is read-only, like `:w!`
+    pub fn save(&mut self, backup: bool, force: bool) -> Result<(), Error> {
+        let Some(file_name) = self.file_name.clone() else {
             return Err(Error::new(std::io::ErrorKind::InvalidInput, "No file name"));
+        };
+        if self.read_only && !force {
+            return Err(Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "File is read-only (add ! to override)",
+            ));
         }
 
-        let file_name = self.file_name.as_ref().unwrap();
-        let mut file = fs::File::create(file_name)?;
-        self.file_type = FileType::from(file_name);
-        for row in &mut self.rows {
-            file.write_all(row.as_bytes())?;
-            file.write_all(b"\n")?;
+        self.check_loaded()?;
+        let contents = self.encode()?;
+        writer::write_atomic(&file_name, &contents, backup)?;
+        self.disk_state = fs::metadata(&file_name).ok().map(|m| DiskState::from(&m));
+        if !self.is_binary() {
+            self.file_type = FileType::from(file_name.as_str());
         }
         self.dirty = false;
+        self.remove_swap();
         Ok(())
     }
 
+    // Writes the buffer to another file, leaving this one's name and state alone
+    pub fn write_copy(&mut self, file_name: &str, backup: bool) -> Result<(), Error> {
+        self.check_loaded()?;
+        let contents = self.encode()?;
+        writer::write_atomic(file_name, &contents, backup)
+    }
+
+    // Writing a partly loaded buffer would truncate the file
+    fn check_loaded(&mut self) -> Result<(), Error> {
+        self.finish_loading()?;
+        match &self.load_error {
+            Some(error) => Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "File was only partly loaded ({}), reload it with :e!",
+                    error
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Encodes the whole buffer up front so a failed conversion never truncates the file
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        if let Some(hex) = &self.hex {
+            return Ok(hex.as_bytes().to_vec());
+        }
+        let ending = self.encoding.encode(self.file_format.line_ending())?;
+        let mut contents = self.encoding.bom().to_vec();
+        let last = self.rows.len().saturating_sub(1);
+        for (index, row) in self.rows.iter().enumerate() {
+            contents.extend(self.encoding.encode(row.as_str())?);
+            if index < last || !self.no_eol {
+                contents.extend_from_slice(&ending);
+            }
+        }
+        Ok(contents)
+    }
+
     pub fn highlight(&mut self, word: &Option<String>, until: Option<usize>) {
         let mut start_with_comment = false;
         let until = until
@@ -159,3 +541,108 @@ impl Document {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("rustvim-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn keeps_line_endings_and_missing_final_newline() {
+        let document = Document::from_bytes(b"one\r\ntwo".to_vec()).unwrap();
+        assert_eq!(document.file_format(), FileFormat::Dos);
+        assert!(!document.has_eol());
+        assert_eq!(document.lines().collect::<Vec<_>>(), ["one", "two"]);
+        assert_eq!(document.encode().unwrap(), b"one\r\ntwo");
+    }
+
+    #[test]
+    fn falls_back_to_latin1_for_invalid_utf8() {
+        let document = Document::from_bytes(b"caf\xE9\n".to_vec()).unwrap();
+        assert_eq!(document.encoding(), Encoding::Latin1);
+        assert_eq!(document.lines().collect::<Vec<_>>(), ["café"]);
+        assert_eq!(document.encode().unwrap(), b"caf\xE9\n");
+    }
+
+    #[test]
+    fn opens_binary_files_as_hex() {
+        let mut document = Document::from_bytes(vec![0, 1, 2, 0xFF]).unwrap();
+        assert!(document.is_binary());
+        document.insert(&Pos { x: 0, y: 0 }, 'x').unwrap();
+        assert_eq!(document.encode().unwrap(), [0, 1, 2, 0xFF]);
+    }
+
+    #[test]
+    fn edits_rows() {
+        let mut document = Document::from_bytes(b"ab\ncd\n".to_vec()).unwrap();
+        document.insert(&Pos { x: 1, y: 0 }, '\n').unwrap();
+        document.insert(&Pos { x: 2, y: 2 }, '!').unwrap();
+        assert_eq!(document.lines().collect::<Vec<_>>(), ["a", "b", "cd!"]);
+        document.delete(&Pos { x: 1, y: 0 }).unwrap();
+        assert_eq!(document.lines().collect::<Vec<_>>(), ["ab", "cd!"]);
+        assert!(document.is_dirty());
+    }
+
+    #[test]
+    fn refuses_changes_to_read_only_buffers() {
+        let mut document = Document::from_bytes(b"ab\n".to_vec()).unwrap();
+        document.set_read_only(true);
+        assert!(document.insert(&Pos { x: 0, y: 0 }, 'x').is_err());
+        assert!(!document.is_dirty());
+    }
+
+    #[test]
+    fn does_not_save_after_a_failed_load() {
+        let file_name = temp_file("partial.txt", b"all of it\n");
+        let mut document = Document {
+            rows: vec![Row::from("part")],
+            load_error: Some("read error".to_string()),
+            ..Document::new(&file_name)
+        };
+        assert!(document.save(false, true).is_err());
+        assert!(document.write_copy(&file_name, false).is_err());
+        assert_eq!(fs::read(&file_name).unwrap(), b"all of it\n");
+        fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn round_trips_large_files_loaded_in_the_background() {
+        let mut contents = "caf\u{e9}\n"
+            .repeat(LAZY_LOAD_THRESHOLD as usize / 5)
+            .into_bytes();
+        contents.extend_from_slice(b"bad \xFF byte\nlast");
+        let file_name = temp_file("large.txt", &contents);
+        let mut document = Document::open(&file_name).unwrap();
+        document.finish_loading().unwrap();
+        assert_eq!(document.encoding(), Encoding::Latin1);
+        assert_eq!(document.row(0).map(Row::as_str), Some("cafÃ©"));
+        // Not assert_eq, which would print megabytes on failure
+        assert!(document.encode().unwrap() == contents);
+        fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn reads_large_dos_files_with_stray_line_feeds_as_unix() {
+        let mut contents = "a\r\n"
+            .repeat(LAZY_LOAD_THRESHOLD as usize / 3)
+            .into_bytes();
+        contents.extend_from_slice(b"stray\nfeed\r\nlast\n");
+        let file_name = temp_file("large-dos.txt", &contents);
+        let mut document = Document::open(&file_name).unwrap();
+        document.finish_loading().unwrap();
+        // The same rows as when the whole file is split at once
+        let eager = Document::from_bytes(contents.clone()).unwrap();
+        assert_eq!(eager.file_format(), FileFormat::Unix);
+        assert_eq!(document.file_format(), FileFormat::Unix);
+        assert!(document.lines().eq(eager.lines()));
+        assert_eq!(document.row(0).map(Row::as_str), Some("a\r"));
+        assert!(document.has_eol());
+        assert!(document.encode().unwrap() == contents);
+        fs::remove_file(&file_name).unwrap();
+    }
+}
@@ -1,14 +1,38 @@
-use crate::{Action, Document, Mode, Row, Terminal, KeyMapConfig};
+use crate::buffer::Buffer;
+use crate::cli::{Args, FileArg};
+use crate::job::{self, Job, JobEvent};
+use crate::options::{self, Kind, OptionDef, Options, Scope, Setting, Value};
+use crate::window::{Layout, Rect, Split, TabPage, Window};
+use crate::{config, diff, keys, swap};
+use crate::{
+    Action, Document, Encoding, FileFormat, Key, KeyMapConfig, KeyMatch, Mapping, Mode, Row,
+};
+use crate::{Backend, Cell, Color, Context, Error, Event, Frame, Result, Size, Waker};
+use serde::Deserialize;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::time::{Duration, Instant};
-use std::{env, io, fs};
-use termion::color;
-use termion::event::Key;
-use serde::{Serialize,Deserialize};
+use std::{env, fs, io, mem};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
-const STATUS_BG_COLOR: color::Rgb = color::Rgb(239, 239, 239);
-const STATUS_FG_COLOR: color::Rgb = color::Rgb(63, 63, 63);
-const KEYMAP_CONFIG_FILE: &str = "qwerty.toml";
+const BINARY_EDIT_MESSAGE: &str = "Binary file: type hex digits or ASCII to overwrite bytes";
+const LOADING_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+// Like Vim's 'timeoutlen'
+const KEY_TIMEOUT: Duration = Duration::from_secs(1);
+// Like Vim's 'maxmapdepth', though lower since each level is a nested call
+const MAX_NESTING: usize = 100;
+// How often the config files are checked for changes
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Used until the frontend reports the real size, and when running headless
+const DEFAULT_SIZE: Size = Size {
+    width: 80,
+    height: 24,
+};
 
 #[derive(Default, Copy, Clone, Debug, Deserialize, PartialEq)]
 pub struct Pos {
@@ -33,6 +57,27 @@ struct StatusMessage {
     time: Instant,
 }
 
+// How a window shows its buffer, from their options. `width` and `height` are
+// those of the text, right of the `gutter` with the line numbers.
+struct View {
+    width: usize,
+    height: usize,
+    gutter: usize,
+    tabstop: usize,
+    wrap: bool,
+    scrolloff: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SetScope {
+    // :set, which sets both the local and the global value
+    Both,
+    // :setlocal
+    Local,
+    // :setglobal
+    Global,
+}
+
 impl StatusMessage {
     fn from(message: String) -> Self {
         Self {
@@ -42,69 +87,1255 @@ impl StatusMessage {
     }
 }
 
+// Something that takes the keys before the current mode does
+enum Interaction {
+    Prompt { kind: Prompt, text: String },
+    Question { kind: Question, text: String },
+    // A full screen list of lines, see `show_lines`
+    Pager { lines: Vec<String>, top: usize },
+    // The first key of a two key command such as <C-w>l or gt, dropped when
+    // the second does not follow within KEY_TIMEOUT
+    Prefix { key: Key, time: Instant },
+    // Keys that start a longer key map sequence; after KEY_TIMEOUT the longest
+    // complete sequence among them is used
+    Mapping { keys: Vec<Key>, time: Instant },
+}
+
+enum Prompt {
+    Command,
+    Search { old_pos: Pos, direction: Direction },
+    SaveAs { force: bool },
+}
+
+impl Prompt {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Command => ":",
+            Self::Search { .. } => "Search (ESC to caecel, Arrows to navigate): ",
+            Self::SaveAs { .. } => "Save as: ",
+        }
+    }
+}
+
+enum Question {
+    // Aborting goes back to buffer `previous`, or quits for files opened at startup
+    Swap {
+        path: PathBuf,
+        buffer_id: usize,
+        previous: Option<usize>,
+    },
+    // `save` holds the force flag of the save that has to wait for the answer
+    DiskChange {
+        save: Option<bool>,
+    },
+}
+
 pub struct Editor {
     cursor_pos: Pos,
     offset: Pos,
     mode: Mode,
-    document: Document,
-    terminal: Terminal,
+    buffers: Vec<Buffer>,
+    current: usize,
+    alternate: Option<usize>,
+    next_buffer_id: usize,
+    windows: Vec<Window>,
+    layout: Layout,
+    active: usize,
+    next_window_id: usize,
+    // The entry at `tab` is a placeholder; the shown tab lives in the fields above
+    tabs: Vec<TabPage>,
+    tab: usize,
+    size: Size,
+    headless: bool,
+    // The front entry gets the next key
+    interactions: VecDeque<Interaction>,
+    // The rest of a composite action, run once the interactions are answered
+    deferred: VecDeque<Action>,
     should_quit: bool,
     status_message: StatusMessage,
+    // The last error shown, until a headless run checks for it
+    error: Option<Error>,
     highlighted_word: Option<String>,
-    keymap_config: KeyMapConfig,
+    keymap: KeyMapConfig,
+    // What `:normal!` looks keys up in, since it skips the user's mappings
+    built_in_keymap: KeyMapConfig,
+    // Set while `:normal!` runs
+    skip_user_mappings: bool,
+    // What :map and :command made, kept when the config files are read again
+    user_keymap: KeyMapConfig,
+    // How deep mappings and user commands that run others are nested
+    nesting: usize,
+    // The config files the key map was built from, in order
+    config_sources: Vec<config::Source>,
+    config_checked: Instant,
+    // The global option values; buffers and windows keep their own
+    options: Options,
+    // What :set made global, kept when the config files are read again
+    user_options: Options,
+    // Set by -R: every buffer opened is read-only
+    read_only: bool,
+    // Wakes the backend when a job has something to report
+    waker: Option<Waker>,
+    jobs: Vec<Job>,
+    next_job_id: usize,
+    job_events: (Sender<JobEvent>, Receiver<JobEvent>),
 }
 
 impl Editor {
-    pub fn default() -> Self {
-        let args: Vec<String> = env::args().collect();
-        let mut initial_status = String::from("HELP: <C-S> = save <C-Q> = quit <C-F> = search");
-        let document = if let Some(filename) = args.get(1) {
-            if let Ok(doc) = Document::open(filename) {
-                doc
-            } else {
-                initial_status = format!("ERR: Could not open file: {}", filename);
-                Document::default()
-            }
-        } else {
-            Document::default()
-        };
-        Terminal::set_cursor_shape(Mode::Normal.cursor_shape());
-        let keymap_config = KeyMapConfig::from(fs::read_to_string(KEYMAP_CONFIG_FILE).unwrap().as_str());
+    pub fn new(mut args: Args) -> Self {
+        let swap_arg = args
+            .files
+            .first()
+            .filter(|file| args.recover && swap::is_swap_path(Path::new(&file.name)))
+            .and_then(|file| swap::read(Path::new(&file.name)).ok());
+        if let Some(info) = &swap_arg {
+            args.files = info
+                .file_name
+                .iter()
+                .map(|name| FileArg {
+                    name: name.clone(),
+                    line: None,
+                    column: None,
+                })
+                .collect();
+        }
+        // The caller can point stdin at the terminal once this has been read
+        let piped = args.stdin.then(|| {
+            let mut bytes = Vec::new();
+            io::stdin().lock().read_to_end(&mut bytes).map(|_| bytes)
+        });
 
-        Self {
+        let mut editor = Self {
             cursor_pos: Pos::default(),
             offset: Pos::default(),
             should_quit: false,
             mode: Mode::Normal,
-            terminal: Terminal::new().expect("Failed to initialize terminal"),
-            document,
-            status_message: StatusMessage::from(initial_status),
+            size: DEFAULT_SIZE,
+            headless: args.headless,
+            interactions: VecDeque::new(),
+            deferred: VecDeque::new(),
+            buffers: Vec::new(),
+            current: 0,
+            alternate: None,
+            next_buffer_id: 1,
+            windows: Vec::new(),
+            layout: Layout::Window(1),
+            active: 0,
+            next_window_id: 2,
+            tabs: vec![TabPage::new(Window::new(0, 0))],
+            tab: 0,
+            status_message: StatusMessage::from(String::from(
+                "HELP: <C-S> = save <C-Q> = quit <C-F> = search",
+            )),
+            error: None,
             highlighted_word: None,
-            keymap_config,
+            keymap: KeyMapConfig::built_in(),
+            built_in_keymap: KeyMapConfig::built_in(),
+            skip_user_mappings: false,
+            user_keymap: KeyMapConfig::default(),
+            nesting: 0,
+            config_sources: Vec::new(),
+            config_checked: Instant::now(),
+            options: Options::default(),
+            user_options: Options::default(),
+            read_only: args.read_only,
+            waker: None,
+            jobs: Vec::new(),
+            next_job_id: 1,
+            job_events: mpsc::channel(),
+        };
+        if !args.clean {
+            editor.config_sources = config::startup_files()
+                .into_iter()
+                .map(config::Source::new)
+                .collect();
+            if Path::new(config::PROJECT_FILE).exists()
+                && !config::is_trusted(Path::new(config::PROJECT_FILE))
+            {
+                editor.status_message = StatusMessage::from(format!(
+                    "Skipped untrusted {}; :trust loads it",
+                    config::PROJECT_FILE
+                ));
+            }
+            editor.load_config();
+        }
+        // Like Vim, the text from stdin comes before any named files
+        if let Some(piped) = piped {
+            match piped
+                .and_then(Document::from_bytes)
+                .context("Could not read standard input")
+            {
+                Ok(mut document) => {
+                    document.set_read_only(editor.read_only);
+                    editor.add_document(document);
+                }
+                Err(error) => editor.report(error),
+            }
+        }
+        for file in &args.files {
+            if let Some(index) = editor.add_buffer(&file.name) {
+                let buffer = &mut editor.buffers[index];
+                buffer.cursor_pos = Pos {
+                    x: file.column.unwrap_or(1).saturating_sub(1),
+                    y: file.line.unwrap_or(1).saturating_sub(1),
+                };
+            }
+        }
+        if editor.buffers.is_empty() {
+            editor.add_document(Document::default());
+        }
+        editor.windows.push(Window::new(1, editor.buffers[0].id));
+        editor.cursor_pos = editor.buffers[0].cursor_pos;
+        editor.clamp_cursor();
+        if let Some(info) = swap_arg {
+            editor.recover_swap(0, &info.path);
+        } else if args.recover {
+            match editor.document().file_name.as_deref().and_then(swap::find) {
+                Some(path) => editor.recover_swap(0, &path),
+                None => editor.fail("No swap file found to recover"),
+            }
+        } else if !args.headless {
+            // Questions go to the front of the queue, so the first file is asked about first
+            for index in (0..editor.buffers.len()).rev() {
+                editor.check_swap(index, None);
+            }
+        }
+        if let Some(split) = args.split {
+            editor.open_in_splits(split, args.max_windows);
+        }
+        editor.scroll();
+        editor
+    }
+
+    pub fn run_commands(&mut self, commands: &[String]) {
+        for command in commands {
+            self.execute_command(command);
         }
     }
 
-    pub fn run(&mut self) {
+    // Runs `commands`, then Ex commands read from stdin if `script` is set, until
+    // one fails or quits. Fails with the first error a command reported.
+    pub fn run_headless(&mut self, commands: &[String], script: bool) -> Result<()> {
+        for buffer in &mut self.buffers {
+            if let Err(error) = buffer
+                .document
+                .finish_loading()
+                .context("Could not finish loading file")
+            {
+                self.report(error);
+                break;
+            }
+        }
+        self.check_headless()?;
+        let mut script_lines = io::stdin().lines();
+        let mut commands = commands.iter().cloned();
         loop {
-            if let Err(error) = self.refresh_screen() {
-                die(&error);
+            let command = match commands.next() {
+                Some(command) => command,
+                None if script => match script_lines.next() {
+                    Some(line) => line.context("Could not read commands")?,
+                    None => return Ok(()),
+                },
+                None => return Ok(()),
+            };
+            self.status_message = StatusMessage::from(String::new());
+            self.execute_command(command.trim().trim_start_matches(':'));
+            self.cancel_interactions(0);
+            self.check_headless()?;
+            if self.should_quit {
+                return Ok(());
             }
+        }
+    }
 
+    // Prints the message of an error reported since the last check, and returns it
+    fn check_headless(&mut self) -> Result<()> {
+        match self.error.take() {
+            Some(error) => {
+                eprintln!("{}", self.status_message.text);
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.should_quit
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn resize(&mut self, size: Size) {
+        self.size = size;
+        self.scroll();
+        self.store_window();
+    }
+
+    // Handles `notation` as if typed in normal mode, with the user's mappings
+    // applied only if `remap` is set
+    fn replay_keys(&mut self, notation: &str, remap: bool) {
+        let skipping = mem::replace(&mut self.skip_user_mappings, !remap);
+        let queued = self.interactions.len();
+        for key in keys::parse(notation) {
             if self.should_quit {
                 break;
             }
+            self.handle_key(key);
+        }
+        self.cancel_interactions(queued);
+        self.skip_user_mappings = skipping;
+        if self.mode != Mode::Normal {
+            self.set_mode(Mode::Normal);
+        }
+    }
+
+    // Like Vim's `:normal`, running out of keys cancels what is still waiting
+    // for them, leaving the first `keep` entries alone
+    fn cancel_interactions(&mut self, keep: usize) {
+        while self.interactions.len() > keep && !self.should_quit {
+            if let Some(Interaction::Mapping { keys, .. }) = self.interactions.front() {
+                let keys = keys.clone();
+                self.interactions.pop_front();
+                self.finish_mapping(keys);
+                continue;
+            }
+            self.handle_key(Key::Esc);
+        }
+    }
+
+    // Shows the first buffers in windows of their own, the first one on top or left
+    fn open_in_splits(&mut self, split: Split, max_windows: Option<usize>) {
+        let count = max_windows.unwrap_or(usize::MAX).min(self.buffers.len());
+        for index in (0..count).rev() {
+            if index < count - 1 {
+                let windows = self.windows.len();
+                self.split_window(split, "");
+                if self.windows.len() == windows {
+                    continue;
+                }
+            }
+            self.switch_to_buffer(index);
+        }
+        self.layout.equalize();
+        self.alternate = None;
+    }
+
+    fn document(&self) -> &Document {
+        &self.buffers[self.current].document
+    }
+
+    fn document_mut(&mut self) -> &mut Document {
+        &mut self.buffers[self.current].document
+    }
+
+    fn add_document(&mut self, document: Document) -> usize {
+        self.buffers
+            .push(Buffer::new(self.next_buffer_id, document));
+        self.next_buffer_id += 1;
+        self.buffers.len() - 1
+    }
+
+    // Opens `file_name` as a new buffer and returns its index
+    fn add_buffer(&mut self, file_name: &str) -> Option<usize> {
+        let mut document = match Document::open(file_name) {
+            Ok(document) => {
+                if document.is_read_only() && !self.read_only {
+                    self.status_message =
+                        StatusMessage::from(format!("\"{}\" [RO] no write permission", file_name));
+                }
+                if document.encoding() == Encoding::Latin1 {
+                    self.status_message = StatusMessage::from(format!(
+                        "{} is not valid UTF-8, opened as latin1",
+                        file_name
+                    ));
+                }
+                document
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                self.status_message = StatusMessage::from(format!("\"{}\" [New File]", file_name));
+                Document::new(file_name)
+            }
+            Err(error) => {
+                self.report(Error::Io {
+                    context: format!("Could not open file: {}", file_name),
+                    source: error,
+                });
+                return None;
+            }
+        };
+        if self.read_only {
+            document.set_read_only(true);
+        }
+        Some(self.add_document(document))
+    }
+
+    fn find_buffer(&self, file_name: &str) -> Option<usize> {
+        let canonical = fs::canonicalize(file_name).ok();
+        self.buffers.iter().position(|buffer| {
+            let Some(name) = buffer.document.file_name.as_deref() else {
+                return false;
+            };
+            name == file_name || (canonical.is_some() && fs::canonicalize(name).ok() == canonical)
+        })
+    }
+
+    // Resolves a buffer number, `#` or a unique part of a buffer name
+    fn resolve_buffer(&mut self, arg: &str) -> Option<usize> {
+        if arg.is_empty() {
+            return Some(self.current);
+        }
+        let found = if arg == "#" {
+            self.alternate
+                .and_then(|id| self.buffers.iter().position(|b| b.id == id))
+                .ok_or("No alternate buffer".to_string())
+        } else if let Ok(id) = arg.parse::<usize>() {
+            self.buffers
+                .iter()
+                .position(|b| b.id == id)
+                .ok_or(format!("Buffer {} does not exist", id))
+        } else if let Some(index) = self.buffers.iter().position(|b| b.name() == arg) {
+            Ok(index)
+        } else {
+            let matches: Vec<usize> = (0..self.buffers.len())
+                .filter(|i| self.buffers[*i].name().contains(arg))
+                .collect();
+            match matches.as_slice() {
+                [index] => Ok(*index),
+                [] => Err(format!("No matching buffer for {}", arg)),
+                _ => Err(format!("More than one match for {}", arg)),
+            }
+        };
+        match found {
+            Ok(index) => Some(index),
+            Err(message) => {
+                self.fail(message);
+                None
+            }
+        }
+    }
+
+    fn switch_to_buffer(&mut self, index: usize) {
+        if index == self.current {
+            return;
+        }
+        let buffer = &mut self.buffers[self.current];
+        buffer.cursor_pos = self.cursor_pos;
+        buffer.offset = self.offset;
+        self.alternate = Some(buffer.id);
+        self.current = index;
+        self.cursor_pos = self.buffers[index].cursor_pos;
+        self.offset = self.buffers[index].offset;
+        self.clamp_cursor();
+        self.scroll();
+        self.store_window();
+    }
+
+    fn edit(&mut self, file_name: &str, force: bool) {
+        if file_name.is_empty() {
+            if self.document().is_dirty() && !force {
+                self.fail("No write since last change (add ! to override)");
+            } else if self.document().file_name.is_some() {
+                self.reload();
+            }
+            return;
+        }
+        if let Some(index) = self.find_buffer(file_name) {
+            self.switch_to_buffer(index);
+            return;
+        }
+        let previous = self.buffers[self.current].id;
+        if let Some(index) = self.add_buffer(file_name) {
+            self.switch_to_buffer(index);
+            self.check_swap(index, Some(previous));
+        }
+    }
+
+    fn cycle_buffer(&mut self, direction: Direction) {
+        let len = self.buffers.len();
+        let index = match direction {
+            Direction::Forward => (self.current + 1) % len,
+            Direction::Backward => (self.current + len - 1) % len,
+        };
+        self.switch_to_buffer(index);
+    }
+
+    fn delete_buffer(&mut self, arg: &str, force: bool) {
+        let Some(index) = self.resolve_buffer(arg) else {
+            return;
+        };
+        let buffer = &self.buffers[index];
+        if buffer.document.is_dirty() && !force {
+            self.fail(format!(
+                "No write since last change for buffer {} (add ! to override)",
+                buffer.id
+            ));
+            return;
+        }
+        self.remove_buffer(index);
+    }
+
+    // Removes buffer `index` and the windows showing it, showing another buffer
+    // in its place if it is the current one
+    fn remove_buffer(&mut self, index: usize) {
+        if index == self.current {
+            let alternate = self
+                .alternate
+                .and_then(|id| self.buffers.iter().position(|b| b.id == id))
+                .filter(|i| *i != index);
+            match alternate {
+                Some(next) => self.switch_to_buffer(next),
+                None if self.buffers.len() > 1 => self.cycle_buffer(Direction::Forward),
+                None => {
+                    self.add_document(Document::default());
+                    self.cycle_buffer(Direction::Forward);
+                }
+            }
+        }
+        let mut removed = self.buffers.remove(index);
+        removed.document.remove_swap();
+        if index < self.current {
+            self.current -= 1;
+        }
+        if self.alternate == Some(removed.id) {
+            self.alternate = None;
+        }
+        while let Some(index) = self
+            .windows
+            .iter()
+            .position(|w| w.buffer_id == removed.id)
+            .filter(|_| self.windows.len() > 1)
+        {
+            self.close_window(index);
+        }
+        let tab = self.tab;
+        for index in (0..self.tabs.len()).filter(|index| *index != tab) {
+            self.switch_to_tab(index);
+            while let Some(index) = self
+                .windows
+                .iter()
+                .position(|w| w.buffer_id == removed.id)
+                .filter(|_| self.windows.len() > 1)
+            {
+                self.close_window(index);
+            }
+        }
+        self.switch_to_tab(tab);
+    }
+
+    fn screen_area(&self) -> Rect {
+        let tab_line = usize::from(self.tabs.len() > 1);
+        Rect {
+            x: 0,
+            y: tab_line,
+            width: self.size.width as usize,
+            // Each window has its own status line, only the message bar is shared
+            height: (self.size.height as usize).saturating_sub(1 + tab_line),
+        }
+    }
+
+    // Exchanges the shown windows with those stored for tab `index`
+    fn swap_tab(&mut self, index: usize) {
+        let tab = &mut self.tabs[index];
+        mem::swap(&mut self.windows, &mut tab.windows);
+        mem::swap(&mut self.layout, &mut tab.layout);
+        mem::swap(&mut self.active, &mut tab.active);
+    }
+
+    fn switch_to_tab(&mut self, index: usize) {
+        if index == self.tab {
+            return;
+        }
+        self.store_window();
+        self.swap_tab(self.tab);
+        self.tab = index;
+        self.swap_tab(index);
+        self.load_window();
+    }
+
+    fn cycle_tab(&mut self, direction: Direction) {
+        let len = self.tabs.len();
+        let index = match direction {
+            Direction::Forward => (self.tab + 1) % len,
+            Direction::Backward => (self.tab + len - 1) % len,
+        };
+        self.switch_to_tab(index);
+    }
+
+    // `:tabnext` goes to the next tab, `:tabnext N` to tab N
+    fn select_tab(&mut self, arg: &str) {
+        if arg.is_empty() {
+            return self.cycle_tab(Direction::Forward);
+        }
+        match arg.parse::<usize>() {
+            Ok(number) if (1..=self.tabs.len()).contains(&number) => self.switch_to_tab(number - 1),
+            _ => self.fail(format!("Invalid tab page: {}", arg)),
+        }
+    }
+
+    fn new_tab(&mut self, file_name: &str) {
+        self.store_window();
+        let mut window = Window::new(self.next_window_id, self.buffers[self.current].id);
+        window.options = self.windows[self.active].options.clone();
+        self.next_window_id += 1;
+        self.tabs.insert(self.tab + 1, TabPage::new(window));
+        self.switch_to_tab(self.tab + 1);
+        if file_name.is_empty() {
+            let index = self.add_document(Document::default());
+            self.switch_to_buffer(index);
+        } else {
+            self.edit(file_name, false);
+        }
+    }
+
+    fn close_tab(&mut self) {
+        if self.tabs.len() == 1 {
+            self.fail("Cannot close last tab page");
+            return;
+        }
+        let index = self.tab;
+        self.switch_to_tab(if index + 1 < self.tabs.len() {
+            index + 1
+        } else {
+            index - 1
+        });
+        self.tabs.remove(index);
+        if index < self.tab {
+            self.tab -= 1;
+        }
+        self.scroll();
+    }
+
+    fn window_rect(&self) -> Rect {
+        self.layout
+            .rect_of(self.windows[self.active].id, self.screen_area())
+            .unwrap_or_default()
+    }
 
-            if let Err(error) = self.handle_input() {
-                die(&error);
+    // The width and height of the text area of the active window
+    fn view_size(&self) -> (usize, usize) {
+        let view = self.active_view();
+        (view.width, view.height)
+    }
+
+    fn active_view(&self) -> View {
+        let window = &self.windows[self.active];
+        self.view(&self.buffers[self.current], window, &self.window_rect())
+    }
+
+    fn view(&self, buffer: &Buffer, window: &Window, rect: &Rect) -> View {
+        let option = |name| self.option_in(name, buffer, window);
+        let gutter = if option("number").is_on() {
+            buffer.document.len().to_string().len().max(3) + 1
+        } else {
+            0
+        };
+        View {
+            width: rect.width.saturating_sub(gutter).max(1),
+            height: rect.height.saturating_sub(1).max(1),
+            gutter: gutter.min(rect.width.saturating_sub(1)),
+            tabstop: option("tabstop").number().max(1),
+            wrap: option("wrap").is_on(),
+            scrolloff: option("scrolloff").number(),
+        }
+    }
+
+    // The editor keeps the active window's cursor and buffer in its own fields
+    fn store_window(&mut self) {
+        let window = &mut self.windows[self.active];
+        window.buffer_id = self.buffers[self.current].id;
+        window.cursor_pos = self.cursor_pos;
+        window.offset = self.offset;
+    }
+
+    fn load_window(&mut self) {
+        let window = &self.windows[self.active];
+        self.current = self
+            .buffers
+            .iter()
+            .position(|b| b.id == window.buffer_id)
+            .unwrap_or(0);
+        self.cursor_pos = window.cursor_pos;
+        self.offset = window.offset;
+        self.clamp_cursor();
+        self.scroll();
+    }
+
+    fn focus_window(&mut self, index: usize) {
+        if index != self.active {
+            self.store_window();
+            self.active = index;
+            self.load_window();
+        }
+    }
+
+    fn split_window(&mut self, split: Split, file_name: &str) {
+        self.store_window();
+        let id = self.next_window_id;
+        let area = self.screen_area();
+        if !self
+            .layout
+            .split(self.windows[self.active].id, id, split, area)
+        {
+            self.fail("Not enough room");
+            return;
+        }
+        self.next_window_id += 1;
+        let mut window = Window::new(id, self.buffers[self.current].id);
+        window.options = self.windows[self.active].options.clone();
+        self.windows.push(window);
+        self.active = self.windows.len() - 1;
+        self.store_window();
+        if !file_name.is_empty() {
+            self.edit(file_name, false);
+        }
+        self.scroll();
+    }
+
+    fn new_window(&mut self, split: Split) {
+        let windows = self.windows.len();
+        self.split_window(split, "");
+        if self.windows.len() > windows {
+            let index = self.add_document(Document::default());
+            self.switch_to_buffer(index);
+        }
+    }
+
+    fn close_window(&mut self, index: usize) {
+        if self.windows.len() == 1 && self.tabs.len() > 1 {
+            return self.close_tab();
+        }
+        if self.windows.len() == 1 {
+            self.fail("Cannot close last window");
+            return;
+        }
+        self.store_window();
+        let area = self.screen_area();
+        let id = self.windows[index].id;
+        let rect = self.layout.rect_of(id, area).unwrap_or_default();
+        self.layout.remove(id, area);
+        self.windows.remove(index);
+        if index < self.active {
+            self.active -= 1;
+        } else if index == self.active {
+            // Move to the window that took over the closed window's space
+            self.active = self
+                .layout
+                .rects(area)
+                .into_iter()
+                .find(|(_, r)| r.contains(rect.x, rect.y))
+                .and_then(|(id, _)| self.windows.iter().position(|w| w.id == id))
+                .unwrap_or(0);
+            self.load_window();
+        }
+        self.scroll();
+    }
+
+    fn only_window(&mut self) {
+        let active = self.windows[self.active].id;
+        let area = self.screen_area();
+        for window in &self.windows {
+            if window.id != active {
+                self.layout.remove(window.id, area);
             }
         }
+        self.windows.retain(|w| w.id == active);
+        self.active = 0;
+        self.scroll();
+    }
+
+    fn cycle_window(&mut self, direction: Direction) {
+        let ids = self.layout.window_ids();
+        let id = self.windows[self.active].id;
+        let position = ids.iter().position(|i| *i == id).unwrap_or(0);
+        let next = match direction {
+            Direction::Forward => ids[(position + 1) % ids.len()],
+            Direction::Backward => ids[(position + ids.len() - 1) % ids.len()],
+        };
+        if let Some(index) = self.windows.iter().position(|w| w.id == next) {
+            self.focus_window(index);
+        }
+    }
+
+    // Moves to the window next to the cursor in the direction of h, j, k or l
+    fn focus_neighbour(&mut self, direction: char) {
+        let rect = self.window_rect();
+        let view = self.active_view();
+        let (x, y) = cursor_cell(self.offset, self.cursor_pos, self.document(), &view);
+        let (x, y) = (rect.x + view.gutter + x, rect.y + y);
+        let point = match direction {
+            'h' => rect.x.checked_sub(2).map(|x| (x, y)),
+            'l' => Some((rect.x + rect.width + 1, y)),
+            'k' => rect.y.checked_sub(1).map(|y| (x, y)),
+            'j' => Some((x, rect.y + rect.height)),
+            _ => None,
+        };
+        let Some((x, y)) = point else {
+            return;
+        };
+        let target = self
+            .layout
+            .rects(self.screen_area())
+            .into_iter()
+            .find(|(_, r)| r.contains(x, y))
+            .and_then(|(id, _)| self.windows.iter().position(|w| w.id == id));
+        if let Some(index) = target {
+            self.focus_window(index);
+        }
+    }
+
+    // `size` counts text lines or columns, as in `:resize`
+    fn resize_window(&mut self, split: Split, size: usize) {
+        let area = self.screen_area();
+        let size = match split {
+            Split::Horizontal => size.saturating_add(1),
+            Split::Vertical => size,
+        };
+        self.layout
+            .resize(self.windows[self.active].id, split, size, area);
+        self.scroll();
+    }
+
+    // The text lines or columns of the active window along `split`
+    fn window_extent(&self, split: Split) -> usize {
+        let (width, height) = self.view_size();
+        match split {
+            Split::Horizontal => height,
+            Split::Vertical => width,
+        }
+    }
+
+    fn resize_window_by(&mut self, split: Split, delta: isize) {
+        let size = self.window_extent(split).saturating_add_signed(delta);
+        self.resize_window(split, size.max(1));
+    }
+
+    // Handles `:resize` and `:vertical resize` arguments: N, +N, -N or nothing to maximize
+    fn resize_command(&mut self, split: Split, arg: &str) {
+        let current = self.window_extent(split);
+        let size = if arg.is_empty() {
+            Ok(usize::MAX)
+        } else if let Some(delta) = arg.strip_prefix('+') {
+            delta
+                .parse::<usize>()
+                .map(|delta| current.saturating_add(delta))
+        } else if let Some(delta) = arg.strip_prefix('-') {
+            delta
+                .parse::<usize>()
+                .map(|delta| current.saturating_sub(delta).max(1))
+        } else {
+            arg.parse::<usize>()
+        };
+        match size {
+            Ok(size) => self.resize_window(split, size),
+            Err(_) => self.fail(format!("Invalid size: {}", arg)),
+        }
+    }
+
+    fn window_command(&mut self, key: Key) {
+        match key {
+            Key::Char('h') | Key::Ctrl('h') | Key::Left | Key::Backspace => {
+                self.focus_neighbour('h')
+            }
+            Key::Char('j') | Key::Ctrl('j') | Key::Down => self.focus_neighbour('j'),
+            Key::Char('k') | Key::Ctrl('k') | Key::Up => self.focus_neighbour('k'),
+            Key::Char('l') | Key::Ctrl('l') | Key::Right => self.focus_neighbour('l'),
+            Key::Char('w') | Key::Ctrl('w') => self.cycle_window(Direction::Forward),
+            Key::Char('W') => self.cycle_window(Direction::Backward),
+            Key::Char('s' | 'S') | Key::Ctrl('s') => self.split_window(Split::Horizontal, ""),
+            Key::Char('v') | Key::Ctrl('v') => self.split_window(Split::Vertical, ""),
+            Key::Char('n') | Key::Ctrl('n') => self.new_window(Split::Horizontal),
+            Key::Char('c' | 'q') | Key::Ctrl('c' | 'q') => self.close_window(self.active),
+            Key::Char('o') | Key::Ctrl('o') => self.only_window(),
+            Key::Char('=') => {
+                self.layout.equalize();
+                self.scroll();
+            }
+            Key::Char('+') => self.resize_window_by(Split::Horizontal, 1),
+            Key::Char('-') => self.resize_window_by(Split::Horizontal, -1),
+            Key::Char('>') => self.resize_window_by(Split::Vertical, 1),
+            Key::Char('<') => self.resize_window_by(Split::Vertical, -1),
+            Key::Char('_') => self.resize_window(Split::Horizontal, usize::MAX),
+            Key::Char('|') => self.resize_window(Split::Vertical, usize::MAX),
+            _ => (),
+        }
+    }
+
+    fn list_buffers(&mut self) {
+        let lines: Vec<String> = self
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buffer)| {
+                let (flag, line) = if index == self.current {
+                    ('%', self.cursor_pos.y + 1)
+                } else if Some(buffer.id) == self.alternate {
+                    ('#', buffer.cursor_pos.y + 1)
+                } else {
+                    (' ', buffer.cursor_pos.y + 1)
+                };
+                let modified = if buffer.document.is_dirty() { '+' } else { ' ' };
+                format!(
+                    "{:3} {}{} \"{}\"  line {}",
+                    buffer.id,
+                    flag,
+                    modified,
+                    buffer.name(),
+                    line
+                )
+            })
+            .collect();
+        self.show_lines(lines);
+    }
+
+    // Asks what to do when buffer `index` has a swap file
+    fn check_swap(&mut self, index: usize, previous: Option<usize>) {
+        let buffer = &self.buffers[index];
+        let Some(path) = buffer.document.file_name.as_deref().and_then(swap::find) else {
+            return;
+        };
+        let Ok(info) = swap::read(&path) else {
+            return;
+        };
+        let running = if info.is_running() {
+            ", still running"
+        } else {
+            ""
+        };
+        let text = format!(
+            "[R]ecover, [O]pen read-only, [D]elete, [A]bort? Found swap {} (pid {}{})",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            info.pid,
+            running
+        );
+        let kind = Question::Swap {
+            path,
+            buffer_id: buffer.id,
+            previous,
+        };
+        self.ask(kind, text);
+    }
+
+    // Returns false when `key` is not one of the answers
+    fn answer_swap(
+        &mut self,
+        key: Key,
+        path: &Path,
+        buffer_id: usize,
+        previous: Option<usize>,
+    ) -> bool {
+        let Some(index) = self.buffers.iter().position(|b| b.id == buffer_id) else {
+            return true;
+        };
+        match key {
+            Key::Char('r' | 'R') => self.recover_swap(index, path),
+            Key::Char('o' | 'O') => {
+                self.buffers[index].document.set_read_only(true);
+                self.status_message = StatusMessage::from("Opened read-only".to_string());
+            }
+            Key::Char('d' | 'D') => {
+                match fs::remove_file(path).context("Could not delete swap file") {
+                    Ok(()) => {
+                        self.status_message =
+                            StatusMessage::from(format!("Deleted swap file {}", path.display()))
+                    }
+                    Err(error) => self.report(error),
+                }
+            }
+            Key::Char('a' | 'A') | Key::Esc => match previous {
+                Some(previous) => {
+                    if let Some(previous) = self.buffers.iter().position(|b| b.id == previous) {
+                        self.switch_to_buffer(previous);
+                    }
+                    self.remove_buffer(index);
+                }
+                None => self.should_quit = true,
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    fn recover_swap(&mut self, index: usize, path: &Path) {
+        match swap::read(path)
+            .with_context(|| format!("Could not read swap file {}", path.display()))
+        {
+            Ok(info) => {
+                self.buffers[index].document.recover(info);
+                let _ = fs::remove_file(path);
+                self.status_message = StatusMessage::from(format!(
+                    "Recovered from {}. Save to keep the changes",
+                    path.display()
+                ));
+            }
+            Err(error) => self.report(error),
+        }
+        if index == self.current {
+            self.clamp_cursor();
+        }
+    }
+
+    fn ask(&mut self, kind: Question, text: String) {
+        self.interactions
+            .push_front(Interaction::Question { kind, text });
+    }
+
+    fn answer(&mut self, kind: &Question, key: Key) -> bool {
+        match kind {
+            Question::Swap {
+                path,
+                buffer_id,
+                previous,
+            } => self.answer_swap(key, path, *buffer_id, *previous),
+            Question::DiskChange { save } => self.answer_disk_change(key, *save),
+        }
+    }
+
+    // Draws frames to `backend` and handles its events until the editor quits
+    // or the backend runs out of input. Between events it wakes up for timers
+    // and jobs.
+    // Fails when the backend does, leaving the swap files for recovery.
+    pub fn run<B: Backend>(&mut self, backend: &mut B) -> Result<()> {
+        self.waker = Some(backend.waker());
+        match panic::catch_unwind(AssertUnwindSafe(|| self.run_loop(backend))) {
+            Ok(result) => result,
+            Err(payload) => {
+                self.save_recovery(&panic_message(payload.as_ref()));
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+
+    fn run_loop<B: Backend>(&mut self, backend: &mut B) -> Result<()> {
+        loop {
+            for index in 0..self.buffers.len() {
+                if let Err(error) = self.buffers[index]
+                    .document
+                    .poll_loading()
+                    .context("Could not finish loading file")
+                {
+                    self.report(error);
+                }
+            }
+
+            let frame = self.render();
+            backend
+                .draw(&frame)
+                .context("Could not draw to the screen")?;
+
+            if self.should_quit {
+                for buffer in &mut self.buffers {
+                    buffer.document.remove_swap();
+                }
+                return Ok(());
+            }
+
+            let timeout = self
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match backend.read_event(timeout) {
+                Ok(Some(event)) => self.handle_event(event),
+                Ok(None) => (),
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(error) => return Err(error).context("Could not read input"),
+            }
+            self.handle_job_events();
+            self.handle_timeouts();
+
+            for index in 0..self.buffers.len() {
+                if let Err(error) = self.buffers[index]
+                    .document
+                    .update_swap()
+                    .context("Could not write swap file")
+                {
+                    self.report(error);
+                }
+            }
+        }
+    }
+
+    // Shows `error`, which also makes a headless run fail
+    fn report(&mut self, error: Error) {
+        self.status_message = StatusMessage::from(format!("ERR: {}", error));
+        self.error = Some(error);
+    }
+
+    fn fail<S: Into<String>>(&mut self, message: S) {
+        self.report(Error::Command(message.into()));
+    }
+
+    // Like `fail`, for messages that come without the "ERR: " prefix
+    fn warn(&mut self, message: String) {
+        self.status_message = StatusMessage::from(message.clone());
+        self.error = Some(Error::Command(message));
+    }
+
+    // Writes the panic message and every modified buffer to a recovery file,
+    // since the swap files may be behind
+    fn save_recovery(&self, message: &str) {
+        let dirty: Vec<&Buffer> = self
+            .buffers
+            .iter()
+            .filter(|buffer| buffer.document.is_dirty())
+            .collect();
+        let mut contents = format!("rustvim {} panicked: {}\n", VERSION, message);
+        for buffer in &dirty {
+            let name = buffer.document.file_name.as_deref().unwrap_or("[No Name]");
+            contents.push_str(&format!("\n--- {}\n", name));
+            for line in buffer.document.lines() {
+                contents.push_str(line);
+                contents.push('\n');
+            }
+        }
+        match swap::write_recovery(&contents) {
+            Ok(path) if dirty.is_empty() => eprintln!("Crash report written to {}", path.display()),
+            Ok(path) => eprintln!(
+                "Unsaved changes to {} buffer(s) written to {}",
+                dirty.len(),
+                path.display()
+            ),
+            Err(error) => {
+                eprintln!("{}", contents);
+                eprintln!("Could not write a recovery file: {}", error);
+            }
+        }
+    }
+
+    // The next time something is due without any event: a message to clear,
+    // a key sequence to give up on, a swap file to write or more of a file to show
+    fn next_deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let message = Some(self.status_message.time + MESSAGE_TIMEOUT)
+            .filter(|time| !self.status_message.text.is_empty() && *time > now);
+        let prefix = self
+            .interactions
+            .iter()
+            .find_map(|interaction| match interaction {
+                Interaction::Prefix { time, .. } | Interaction::Mapping { time, .. } => {
+                    Some(*time + KEY_TIMEOUT)
+                }
+                _ => None,
+            });
+        let config =
+            (!self.config_sources.is_empty()).then(|| self.config_checked + CONFIG_CHECK_INTERVAL);
+        let swap = self
+            .buffers
+            .iter()
+            .filter_map(|buffer| buffer.document.swap_due_at())
+            .min();
+        let loading = self
+            .buffers
+            .iter()
+            .any(|buffer| buffer.document.is_loading())
+            .then(|| now + LOADING_REFRESH_INTERVAL);
+        [message, prefix, swap, loading, config]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn handle_timeouts(&mut self) {
+        match self.interactions.front() {
+            Some(Interaction::Prefix { time, .. }) if time.elapsed() >= KEY_TIMEOUT => {
+                self.interactions.pop_front();
+            }
+            Some(Interaction::Mapping { time, .. }) if time.elapsed() >= KEY_TIMEOUT => {
+                if let Some(Interaction::Mapping { keys, .. }) = self.interactions.pop_front() {
+                    self.finish_mapping(keys);
+                    self.scroll();
+                }
+            }
+            _ => (),
+        }
+        if self.config_checked.elapsed() >= CONFIG_CHECK_INTERVAL {
+            self.config_checked = Instant::now();
+            if let Some(source) = self.config_sources.iter().find(|source| source.changed()) {
+                let message = format!("Reloaded {}", source.path.display());
+                self.status_message = StatusMessage::from(message);
+                self.load_config();
+            }
+        }
+    }
+
+    // Builds the key map and the global options from the built-in ones and the
+    // config files. The first error is reported; the entries with errors and
+    // files that cannot be read at all are skipped.
+    fn load_config(&mut self) {
+        let mut keymap = KeyMapConfig::built_in();
+        let mut options = Options::default();
+        let mut error = None;
+        for source in &mut self.config_sources {
+            source.refresh();
+            match config::load(&source.path, &options) {
+                Ok(Some((config, diagnostics))) => {
+                    keymap.merge(config.keymap);
+                    for (option, value) in config.options {
+                        options.set(option, value);
+                    }
+                    let first = diagnostics.into_iter().find(|d| !d.warning);
+                    error = error.or(first.map(|d| d.error));
+                }
+                Ok(None) => (),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        keymap.merge(self.user_keymap.clone());
+        options.merge(&self.user_options);
+        self.keymap = keymap;
+        self.options = options;
+        if let Some(error) = error {
+            self.report(error);
+        }
+    }
+
+    fn source(&mut self, arg: &str) {
+        if arg.is_empty() {
+            self.fail("Argument required");
+            return;
+        }
+        let path = PathBuf::from(arg);
+        if !path.is_file() {
+            self.fail(format!("Can't open file {}", arg));
+            return;
+        }
+        self.status_message = StatusMessage::from(format!("Sourced {}", arg));
+        if !self.config_sources.iter().any(|source| source.path == path) {
+            self.config_sources.push(config::Source::new(path));
+        }
+        self.load_config();
+    }
+
+    fn trust(&mut self) {
+        let path = Path::new(config::PROJECT_FILE);
+        if let Err(error) = config::trust(path) {
+            return self.report(error);
+        }
+        self.status_message = StatusMessage::from(format!("Trusted {}", path.display()));
+        if !self.config_sources.iter().any(|source| source.path == path) {
+            self.config_sources
+                .push(config::Source::new(path.to_path_buf()));
+        }
+        self.load_config();
+    }
+
+    pub fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Key(key) => self.handle_key(key),
+            Event::Resize(size) => self.resize(size),
+            Event::FocusGained => self.check_all_disk_changes(),
+        }
     }
 
     fn handle_action(&mut self, action: &Action) {
         match action {
             Action::Composite(actions) => {
-                for a in actions {
+                for (index, a) in actions.iter().enumerate() {
                     self.handle_action(a);
+                    if !self.interactions.is_empty() {
+                        self.deferred.extend(actions[index + 1..].iter().cloned());
+                        break;
+                    }
                 }
             }
             Action::SetMode(mode) => self.set_mode(*mode),
@@ -117,11 +1348,11 @@ impl Editor {
             Action::MoveCursorDown => self.move_cursor(RelativePos { x: 0, y: 1 }),
             Action::MoveCursorPageUp => self.move_cursor(RelativePos {
                 x: 0,
-                y: -(self.terminal.size().height as isize),
+                y: -(self.view_size().1 as isize),
             }),
             Action::MoveCursorPageDown => self.move_cursor(RelativePos {
                 x: 0,
-                y: self.terminal.size().height as isize,
+                y: self.view_size().1 as isize,
             }),
             Action::MoveCursorHome => self.move_cursor(RelativePos {
                 x: -(self.cursor_pos.x as isize),
@@ -129,7 +1360,7 @@ impl Editor {
             }),
             Action::MoveCursorEnd => self.move_cursor(RelativePos {
                 x: self
-                    .document
+                    .document()
                     .row(self.cursor_pos.y)
                     .map(|r| r.len())
                     .unwrap_or(0) as isize
@@ -137,368 +1368,1427 @@ impl Editor {
                 y: 0,
             }),
             Action::Search => self.search(),
+            Action::NextTab => self.cycle_tab(Direction::Forward),
+            Action::PreviousTab => self.cycle_tab(Direction::Backward),
             Action::Quit => self.quit(),
             Action::Exit => self.should_quit = true,
-            Action::Save => self.save(),
+            Action::Save => self.save(false),
+            Action::ForceSave => self.save(true),
             Action::None => (),
         }
     }
 
-    fn handle_input(&mut self) -> Result<(), io::Error> {
-        match self.mode {
-            Mode::Normal => self.handle_normal_mode_input()?,
-            Mode::Insert => self.handle_insert_mode_input()?,
-            Mode::Visual => self.handle_visual_mode_input()?,
-            Mode::Command => self.handle_command_mode_input()?,
+    // Feeds one key to whatever is waiting for it: an interaction, or else the mode
+    pub fn handle_key(&mut self, key: Key) {
+        self.feed_key(key, true);
+    }
+
+    // Keys from a mapping that is not remapped skip the key map
+    fn feed_key(&mut self, key: Key, remap: bool) {
+        match self.interactions.pop_front() {
+            Some(interaction) => self.handle_interaction_key(interaction, key),
+            None if remap => self.handle_mapped_key(Vec::new(), key),
+            None => self.handle_mode_key(key),
+        }
+        while self.interactions.is_empty() && !self.should_quit {
+            let Some(action) = self.deferred.pop_front() else {
+                break;
+            };
+            self.handle_action(&action);
+        }
+        self.scroll();
+    }
+
+    fn handle_mode_key(&mut self, key: Key) {
+        match self.mode {
+            Mode::Normal => self.handle_normal_mode_input(key),
+            Mode::Insert => self.handle_insert_mode_input(key),
+            Mode::Visual => self.handle_visual_mode_input(key),
+            Mode::Command => self.handle_prompt_key(Prompt::Command, String::new(), key),
+        }
+    }
+
+    // Looks the keys typed so far up in the key map, waiting for more while they
+    // could still become a longer sequence
+    fn handle_mapped_key(&mut self, mut keys: Vec<Key>, key: Key) {
+        keys.push(key);
+        match self.lookup_keys(&keys) {
+            KeyMatch::Prefix { .. } => self.interactions.push_front(Interaction::Mapping {
+                keys,
+                time: Instant::now(),
+            }),
+            KeyMatch::Exact(mapping) => self.run_mapping(&keys, mapping.clone()),
+            KeyMatch::None if keys.len() == 1 => self.handle_mode_key(key),
+            KeyMatch::None => {
+                keys.pop();
+                self.finish_mapping(keys);
+                self.handle_key(key);
+            }
+        }
+    }
+
+    fn lookup_keys(&self, keys: &[Key]) -> KeyMatch<'_> {
+        let keymap = if self.skip_user_mappings {
+            &self.built_in_keymap
+        } else {
+            &self.keymap
+        };
+        keymap.lookup(self.mode, keys)
+    }
+
+    // Runs the sequence `keys` is, or else gives its first key to the mode and
+    // looks the rest up again
+    fn finish_mapping(&mut self, mut keys: Vec<Key>) {
+        match self.lookup_keys(&keys) {
+            KeyMatch::Exact(mapping)
+            | KeyMatch::Prefix {
+                exact: Some(mapping),
+            } => self.run_mapping(&keys, mapping.clone()),
+            _ => {
+                let first = keys.remove(0);
+                self.handle_mode_key(first);
+                for key in keys {
+                    self.handle_key(key);
+                }
+            }
+        }
+    }
+
+    // Like Vim, when a remapped sequence starts with the keys it is mapped from,
+    // those keys are not looked up again
+    fn run_mapping(&mut self, lhs: &[Key], mapping: Mapping) {
+        let (keys, remap) = match mapping {
+            Mapping::Actions(actions) => return self.handle_action(&Action::Composite(actions)),
+            Mapping::Keys { keys, remap } => (keys, remap),
+        };
+        if self.nesting >= MAX_NESTING {
+            self.fail("Recursive mapping");
+            return;
+        }
+        self.nesting += 1;
+        let literal = if remap && keys.starts_with(lhs) {
+            lhs.len()
+        } else {
+            0
+        };
+        for (index, key) in keys.into_iter().enumerate() {
+            if self.should_quit {
+                break;
+            }
+            self.feed_key(key, remap && index >= literal);
+        }
+        self.nesting -= 1;
+    }
+
+    fn handle_interaction_key(&mut self, interaction: Interaction, key: Key) {
+        match interaction {
+            Interaction::Prompt { kind, text } => self.handle_prompt_key(kind, text, key),
+            Interaction::Question { kind, text } => {
+                self.status_message = StatusMessage::from(String::new());
+                let queued = self.interactions.len();
+                if !self.answer(&kind, key) {
+                    // Ask again once whatever the answer opened, like a diff, is closed
+                    let opened = self.interactions.len() - queued;
+                    self.interactions
+                        .insert(opened, Interaction::Question { kind, text });
+                }
+            }
+            Interaction::Pager { lines, top } => self.handle_pager_key(lines, top, key),
+            Interaction::Prefix {
+                key: Key::Ctrl('w'),
+                ..
+            } => self.window_command(key),
+            Interaction::Mapping { keys, .. } => self.handle_mapped_key(keys, key),
+            Interaction::Prefix { .. } => {
+                let action = match key {
+                    Key::Char('t') => Action::NextTab,
+                    Key::Char('T') => Action::PreviousTab,
+                    _ => Action::None,
+                };
+                self.handle_action(&action);
+            }
+        }
+    }
+
+    fn handle_normal_mode_input(&mut self, key: Key) {
+        let action = match key {
+            Key::Esc => Action::None,
+            Key::Ctrl('q') => Action::Quit,
+            Key::Ctrl('x') => Action::Exit,
+            Key::Ctrl('s') => Action::Save,
+            Key::Ctrl('w') | Key::Char('g') => {
+                self.interactions.push_front(Interaction::Prefix {
+                    key,
+                    time: Instant::now(),
+                });
+                return;
+            }
+            Key::Char('i') => Action::SetMode(Mode::Insert),
+            Key::Char('v') => Action::SetMode(Mode::Visual),
+            Key::Char(':') => Action::SetMode(Mode::Command),
+            Key::Char('/') => Action::Search,
+            Key::Left => Action::MoveCursorLeft,
+            Key::Right => Action::MoveCursorRight,
+            Key::Up => Action::MoveCursorUp,
+            Key::Down => Action::MoveCursorDown,
+            Key::PageUp => Action::MoveCursorPageUp,
+            Key::PageDown => Action::MoveCursorPageDown,
+            Key::Home => Action::MoveCursorHome,
+            Key::End => Action::MoveCursorEnd,
+            _ => Action::None,
+        };
+        self.handle_action(&action);
+    }
+
+    fn handle_insert_mode_input(&mut self, key: Key) {
+        let action = match key {
+            Key::Esc => Action::SetMode(Mode::Normal),
+            Key::Char(c) => Action::InsertChar(c),
+            Key::Delete => Action::DeleteChar(Direction::Forward),
+            Key::Backspace => Action::DeleteChar(Direction::Backward),
+            Key::Left => Action::MoveCursorLeft,
+            Key::Right => Action::MoveCursorRight,
+            Key::Up => Action::MoveCursorUp,
+            Key::Down => Action::MoveCursorDown,
+            Key::PageUp => Action::MoveCursorPageUp,
+            Key::PageDown => Action::MoveCursorPageDown,
+            Key::Home => Action::MoveCursorHome,
+            Key::End => Action::MoveCursorEnd,
+            _ => Action::None,
+        };
+        self.handle_action(&action);
+    }
+
+    fn handle_visual_mode_input(&mut self, key: Key) {
+        let action = match key {
+            Key::Esc => Action::SetMode(Mode::Normal),
+            Key::Char(':') => Action::SetMode(Mode::Command),
+            Key::Char('/') => Action::Search,
+            Key::Left => Action::MoveCursorLeft,
+            Key::Right => Action::MoveCursorRight,
+            Key::Up => Action::MoveCursorUp,
+            Key::Down => Action::MoveCursorDown,
+            Key::PageUp => Action::MoveCursorPageUp,
+            Key::PageDown => Action::MoveCursorPageDown,
+            Key::Home => Action::MoveCursorHome,
+            Key::End => Action::MoveCursorEnd,
+            _ => Action::None,
+        };
+        self.handle_action(&action);
+    }
+
+    fn execute_command(&mut self, command: &str) {
+        // Like Vim, colons before the command are skipped
+        let command = command.trim_start_matches([':', ' ']);
+        if let Ok(line) = command.parse::<usize>() {
+            return self.goto_line(line);
+        }
+        if command == "$" {
+            return self.goto_line(usize::MAX);
+        }
+        if let Some(pattern) = command.strip_prefix('/') {
+            return self.search_next(pattern);
+        }
+        if let Some(command) = command.strip_prefix('!') {
+            return self.shell_command(command.trim());
+        }
+        let (name, arg) = command
+            .split_once(' ')
+            .map(|(name, arg)| (name, arg.trim()))
+            .unwrap_or((command, ""));
+        let action = match name {
+            "se" | "set" => return self.set_options(arg, SetScope::Both),
+            "setl" | "setlocal" => return self.set_options(arg, SetScope::Local),
+            "setg" | "setglobal" => return self.set_options(arg, SetScope::Global),
+            "so" | "source" => return self.source(arg),
+            "trust" => return self.trust(),
+            "map" => return self.map_keys(arg, &[Mode::Normal, Mode::Visual], true),
+            "nm" | "nmap" => return self.map_keys(arg, &[Mode::Normal], true),
+            "vm" | "vmap" => return self.map_keys(arg, &[Mode::Visual], true),
+            "im" | "imap" => return self.map_keys(arg, &[Mode::Insert], true),
+            "no" | "noremap" => return self.map_keys(arg, &[Mode::Normal, Mode::Visual], false),
+            "nn" | "nnoremap" => return self.map_keys(arg, &[Mode::Normal], false),
+            "vn" | "vnoremap" => return self.map_keys(arg, &[Mode::Visual], false),
+            "ino" | "inoremap" => return self.map_keys(arg, &[Mode::Insert], false),
+            "unm" | "unmap" => return self.unmap_keys(arg, &[Mode::Normal, Mode::Visual]),
+            "nun" | "nunmap" => return self.unmap_keys(arg, &[Mode::Normal]),
+            "vu" | "vunmap" => return self.unmap_keys(arg, &[Mode::Visual]),
+            "iu" | "iunmap" => return self.unmap_keys(arg, &[Mode::Insert]),
+            "com" | "command" => return self.define_command(arg, false),
+            "com!" | "command!" => return self.define_command(arg, true),
+            "delc" | "delcommand" => return self.delete_command(arg),
+            "norm" | "normal" => return self.replay_keys(arg, true),
+            "norm!" | "normal!" => return self.replay_keys(arg, false),
+            "e" | "edit" => return self.edit(arg, false),
+            "e!" | "edit!" => return self.edit(arg, true),
+            "ls" | "buffers" | "files" => return self.list_buffers(),
+            "b" | "buffer" => {
+                if let Some(index) = self.resolve_buffer(arg) {
+                    self.switch_to_buffer(index);
+                }
+                return;
+            }
+            "bn" | "bnext" => return self.cycle_buffer(Direction::Forward),
+            "bp" | "bprevious" | "bN" | "bNext" => return self.cycle_buffer(Direction::Backward),
+            "bd" | "bdelete" => return self.delete_buffer(arg, false),
+            "bd!" | "bdelete!" => return self.delete_buffer(arg, true),
+            "sp" | "split" => return self.split_window(Split::Horizontal, arg),
+            "vs" | "vsplit" => return self.split_window(Split::Vertical, arg),
+            "new" => return self.new_window(Split::Horizontal),
+            "vne" | "vnew" => return self.new_window(Split::Vertical),
+            "clo" | "close" => return self.close_window(self.active),
+            "on" | "only" => return self.only_window(),
+            "res" | "resize" => return self.resize_command(Split::Horizontal, arg),
+            "vert" | "vertical" => {
+                let (name, arg) = arg
+                    .split_once(' ')
+                    .map(|(name, arg)| (name, arg.trim()))
+                    .unwrap_or((arg, ""));
+                match name {
+                    "res" | "resize" => self.resize_command(Split::Vertical, arg),
+                    "sp" | "split" => self.split_window(Split::Vertical, arg),
+                    "new" => self.new_window(Split::Vertical),
+                    _ => self.fail(format!("Not an editor command: {}", command)),
+                }
+                return;
+            }
+            "w" | "w!" if !arg.is_empty() => return self.write_to(arg, name == "w!"),
+            "w" => Action::Save,
+            "w!" => Action::ForceSave,
+            "q" if self.windows.len() > 1 || self.tabs.len() > 1 => {
+                return self.close_window(self.active)
+            }
+            "tabnew" | "tabe" | "tabedit" => return self.new_tab(arg),
+            "tabn" | "tabnext" => return self.select_tab(arg),
+            "tabp" | "tabprevious" | "tabN" | "tabNext" => {
+                return self.cycle_tab(Direction::Backward)
+            }
+            "tabc" | "tabclose" => return self.close_tab(),
+            "q" => Action::Quit,
+            "qa" | "qall" => Action::Quit,
+            "q!" | "qa!" | "qall!" => Action::Exit,
+            "x" | "wq" => Action::Composite(vec![Action::Save, Action::Exit]),
+            "x!" | "wq!" => Action::Composite(vec![Action::ForceSave, Action::Exit]),
+            "" => Action::None,
+            _ => {
+                if let Some(user_command) = self.keymap.command(name) {
+                    return self.run_user_command(user_command.to_string(), arg);
+                }
+                self.fail(format!("Not an editor command: {}", command));
+                Action::None
+            }
+        };
+        self.handle_action(&action);
+    }
+
+    // `:map lhs rhs` maps the keys `lhs` to `rhs` in `modes`; `:map lhs` lists
+    // the mappings starting with `lhs` and `:map` all of them
+    fn map_keys(&mut self, arg: &str, modes: &[Mode], remap: bool) {
+        let leaders = self.options.leaders();
+        let (lhs, rhs) = arg
+            .split_once(char::is_whitespace)
+            .map(|(lhs, rhs)| (lhs, rhs.trim_start()))
+            .unwrap_or((arg, ""));
+        let lhs = leaders.parse(lhs);
+        if rhs.is_empty() {
+            return self.list_mappings(&lhs, modes);
+        }
+        let mapping = Mapping::Keys {
+            keys: leaders.parse(rhs),
+            remap,
+        };
+        for mode in modes {
+            self.keymap.map(*mode, lhs.clone(), mapping.clone());
+            self.user_keymap.map(*mode, lhs.clone(), mapping.clone());
+        }
+    }
+
+    fn list_mappings(&mut self, prefix: &[Key], modes: &[Mode]) {
+        let mut lines = Vec::new();
+        for mode in modes {
+            let letter = mode.name().to_lowercase().remove(0);
+            for (keys, mapping) in self.keymap.mappings(*mode) {
+                if keys.starts_with(prefix) {
+                    lines.push(format!(
+                        "{}  {:<12} {}",
+                        letter,
+                        keys::notation(keys),
+                        mapping
+                    ));
+                }
+            }
+        }
+        if lines.is_empty() {
+            self.status_message = StatusMessage::from("No mapping found".to_string());
+        } else {
+            self.show_lines(lines);
+        }
+    }
+
+    // Mappings from the config files come back when those are read again
+    fn unmap_keys(&mut self, arg: &str, modes: &[Mode]) {
+        let keys = self.options.leaders().parse(arg);
+        let mut found = false;
+        for mode in modes {
+            found |= self.keymap.unmap(*mode, &keys);
+            self.user_keymap.unmap(*mode, &keys);
+        }
+        if !found {
+            self.fail(format!("No such mapping: {}", arg));
+        }
+    }
+
+    // `:command Name cmd` defines the Ex command `Name`, in which `<args>`
+    // stands for the arguments it is given; `:command` lists them
+    fn define_command(&mut self, arg: &str, replace: bool) {
+        let (name, command) = arg
+            .split_once(char::is_whitespace)
+            .map(|(name, command)| (name, command.trim_start()))
+            .unwrap_or((arg, ""));
+        if command.is_empty() {
+            let lines: Vec<String> = self
+                .keymap
+                .commands()
+                .iter()
+                .filter(|(defined, _)| defined.starts_with(name))
+                .map(|(name, command)| format!("    {:<12} {}", name, command))
+                .collect();
+            if lines.is_empty() {
+                self.status_message =
+                    StatusMessage::from("No user-defined commands found".to_string());
+            } else {
+                self.show_lines([vec![format!("    {:<12} Definition", "Name")], lines].concat());
+            }
+            return;
+        }
+        if !replace && self.keymap.command(name).is_some() {
+            self.fail(format!(
+                "Command already exists: add ! to replace it: {}",
+                name
+            ));
+            return;
+        }
+        if let Err(message) = self.user_keymap.define_command(name, command) {
+            self.fail(message);
+            return;
+        }
+        let _ = self.keymap.define_command(name, command);
+    }
+
+    fn delete_command(&mut self, name: &str) {
+        self.user_keymap.delete_command(name);
+        if !self.keymap.delete_command(name) {
+            self.fail(format!("No such user-defined command: {}", name));
+        }
+    }
+
+    fn run_user_command(&mut self, command: String, arg: &str) {
+        if !arg.is_empty() && !command.contains("<args>") {
+            self.fail(format!("Trailing characters: {}", arg));
+            return;
+        }
+        if self.nesting >= MAX_NESTING {
+            self.fail("Recursive user command");
+            return;
+        }
+        self.nesting += 1;
+        self.execute_command(&command.replace("<args>", arg));
+        self.nesting -= 1;
+    }
+
+    // Lines are counted from 1, as shown in the status bar
+    fn goto_line(&mut self, line: usize) {
+        let last = self.document().len().saturating_sub(1);
+        self.cursor_pos = Pos {
+            x: 0,
+            y: line.saturating_sub(1).min(last),
+        };
+        self.scroll();
+    }
+
+    // Moves to the next match of `pattern`, wrapping around the end of the file
+    fn search_next(&mut self, pattern: &str) {
+        let next = Pos {
+            x: self.cursor_pos.x + 1,
+            y: self.cursor_pos.y,
+        };
+        let ignore_case = self.ignore_case(pattern);
+        let found = self
+            .document()
+            .find(pattern, &next, Direction::Forward, ignore_case)
+            .or_else(|| {
+                self.document()
+                    .find(pattern, &Pos::default(), Direction::Forward, ignore_case)
+            });
+        match found {
+            Some(pos) => {
+                self.cursor_pos = pos;
+                self.scroll();
+            }
+            None => self.fail(format!("Pattern not found: {}", pattern)),
+        }
+    }
+
+    // `:set` lists the options that differ from their defaults and `:set all`
+    // lists every one; otherwise each argument is applied in turn
+    fn set_options(&mut self, args: &str, scope: SetScope) {
+        if args.is_empty() || args == "all" {
+            let mut lines = vec!["--- Options ---".to_string()];
+            for option in options::OPTIONS {
+                let value = self.option_at(option, scope);
+                if args == "all" || value != option.default_value() {
+                    lines.push(format!("  {}", option.show(&value)));
+                }
+            }
+            return self.show_lines(lines);
+        }
+        let mut shown = Vec::new();
+        for arg in args.split_whitespace() {
+            match self.apply_setting(arg, scope) {
+                Ok(Some(text)) => shown.push(text),
+                Ok(None) => (),
+                Err(message) => {
+                    self.fail(message);
+                    return;
+                }
+            }
+        }
+        if !shown.is_empty() {
+            self.status_message = StatusMessage::from(shown.join(" "));
         }
         self.scroll();
-        Ok(())
     }
 
-    fn handle_normal_mode_input(&mut self) -> Result<(), io::Error> {
-        let key = Terminal::read_key()?;
-        let action = match key {
-            Key::Esc => Action::None,
-            Key::Ctrl('q') => Action::Quit,
-            Key::Ctrl('x') => Action::Exit,
-            Key::Ctrl('s') => Action::Save,
-            Key::Char('i') => Action::SetMode(Mode::Insert),
-            Key::Char('v') => Action::SetMode(Mode::Visual),
-            Key::Char(':') => Action::SetMode(Mode::Command),
-            Key::Char('/') => Action::Search,
-            Key::Left => Action::MoveCursorLeft,
-            Key::Right => Action::MoveCursorRight,
-            Key::Up => Action::MoveCursorUp,
-            Key::Down => Action::MoveCursorDown,
-            Key::PageUp => Action::MoveCursorPageUp,
-            Key::PageDown => Action::MoveCursorPageDown,
-            Key::Home => Action::MoveCursorHome,
-            Key::End => Action::MoveCursorEnd,
-            _ => Action::None,
+    // Returns what to show for `name?`
+    fn apply_setting(
+        &mut self,
+        arg: &str,
+        scope: SetScope,
+    ) -> std::result::Result<Option<String>, String> {
+        let (option, setting) = options::parse_setting(arg)?;
+        let current = self.option_at(option, scope);
+        let value = match setting {
+            Setting::Show => return Ok(Some(option.show(&current))),
+            Setting::Name if option.kind != Kind::Bool => return Ok(Some(option.show(&current))),
+            Setting::Name => Value::Bool(true),
+            Setting::Off => Value::Bool(false),
+            Setting::Toggle if option.kind == Kind::Bool => Value::Bool(!current.is_on()),
+            Setting::Toggle => return Err(format!("Invalid argument: {}", arg)),
+            Setting::Reset => option.default_value(),
+            Setting::Assign(text) => option.parse(&text)?,
         };
-        self.handle_action(&action);
-        Ok(())
+        if options::is_per_document(option) {
+            return self.set_document_option(option, value).map(|()| None);
+        }
+        let local = match option.scope {
+            Scope::Global => None,
+            Scope::Buffer => Some(&mut self.buffers[self.current].options),
+            Scope::Window => Some(&mut self.windows[self.active].options),
+        };
+        match (scope, local) {
+            (SetScope::Local, Some(local)) => local.set(option, value),
+            (SetScope::Both, Some(local)) => {
+                local.set(option, value.clone());
+                self.set_global(option, value);
+            }
+            _ => self.set_global(option, value),
+        }
+        Ok(None)
     }
 
-    fn handle_insert_mode_input(&mut self) -> Result<(), io::Error> {
-        let key = Terminal::read_key()?;
-        let action = match key {
-            Key::Esc => Action::SetMode(Mode::Normal),
-            Key::Char(c) => Action::InsertChar(c),
-            Key::Delete => Action::DeleteChar(Direction::Forward),
-            Key::Backspace => Action::DeleteChar(Direction::Backward),
-            Key::Left => Action::MoveCursorLeft,
-            Key::Right => Action::MoveCursorRight,
-            Key::Up => Action::MoveCursorUp,
-            Key::Down => Action::MoveCursorDown,
-            Key::PageUp => Action::MoveCursorPageUp,
-            Key::PageDown => Action::MoveCursorPageDown,
-            Key::Home => Action::MoveCursorHome,
-            Key::End => Action::MoveCursorEnd,
-            _ => Action::None,
-        };
-        self.handle_action(&action);
-        Ok(())
+    fn set_global(&mut self, option: &OptionDef, value: Value) {
+        self.user_options.set(option, value.clone());
+        self.options.set(option, value);
     }
 
-    fn handle_visual_mode_input(&mut self) -> Result<(), io::Error> {
-        let key = Terminal::read_key()?;
-        let action = match key {
-            Key::Esc => Action::SetMode(Mode::Normal),
-            Key::Char(':') => Action::SetMode(Mode::Command),
-            Key::Char('/') => Action::Search,
-            Key::Left => Action::MoveCursorLeft,
-            Key::Right => Action::MoveCursorRight,
-            Key::Up => Action::MoveCursorUp,
-            Key::Down => Action::MoveCursorDown,
-            Key::PageUp => Action::MoveCursorPageUp,
-            Key::PageDown => Action::MoveCursorPageDown,
-            Key::Home => Action::MoveCursorHome,
-            Key::End => Action::MoveCursorEnd,
-            _ => Action::None,
-        };
-        self.handle_action(&action);
+    fn set_document_option(
+        &mut self,
+        option: &OptionDef,
+        value: Value,
+    ) -> std::result::Result<(), String> {
+        let text = value.to_string();
+        match option.name {
+            "fileformat" => {
+                let format = FileFormat::from_name(&text)
+                    .ok_or_else(|| format!("Invalid fileformat: {}", text))?;
+                self.document_mut().set_file_format(format);
+            }
+            "fileencoding" => {
+                let encoding = Encoding::from_name(&text)
+                    .ok_or_else(|| format!("Invalid fileencoding: {}", text))?;
+                self.document_mut().set_encoding(encoding);
+            }
+            _ => self.document_mut().set_read_only(value.is_on()),
+        }
         Ok(())
     }
 
-    fn handle_command_mode_input(&mut self) -> Result<(), io::Error> {
-        let command = self.prompt(":", |_, _, _| {}).unwrap_or(None).unwrap_or_default();
-        let action = match command.as_str() {
-            "w" => Action::Save,
-            "q" => Action::Quit,
-            "x" | "wq" => Action::Composite(vec![Action::Save, Action::Exit]),
-            _ => Action::SetMode(Mode::Normal),
+    // The value `:set` shows for `option`: the global one for `:setglobal`,
+    // otherwise the one in effect here
+    fn option_at(&self, option: &OptionDef, scope: SetScope) -> Value {
+        let (buffer, window) = (&self.buffers[self.current], &self.windows[self.active]);
+        if scope == SetScope::Global && !options::is_per_document(option) {
+            return self
+                .options
+                .get(option)
+                .cloned()
+                .unwrap_or_else(|| option.default_value());
+        }
+        self.option_in(option.name, buffer, window)
+    }
+
+    // The value of option `name` for the current buffer and window
+    fn option(&self, name: &str) -> Value {
+        self.option_in(
+            name,
+            &self.buffers[self.current],
+            &self.windows[self.active],
+        )
+    }
+
+    // An option's value in `buffer` shown in `window`: their own value if they
+    // have one, else the global one
+    fn option_in(&self, name: &str, buffer: &Buffer, window: &Window) -> Value {
+        let option = options::find(name).expect("options are looked up by their names");
+        let document = &buffer.document;
+        let local = match option.name {
+            "fileformat" => return Value::Text(document.file_format().name().to_string()),
+            "fileencoding" => return Value::Text(document.encoding().name().to_string()),
+            "readonly" => return Value::Bool(document.is_read_only()),
+            _ => match option.scope {
+                Scope::Global => None,
+                Scope::Buffer => buffer.options.get(option),
+                Scope::Window => window.options.get(option),
+            },
         };
-        self.handle_action(&action);
-        Ok(())
+        local
+            .or_else(|| self.options.get(option))
+            .cloned()
+            .unwrap_or_else(|| option.default_value())
+    }
+
+    fn color(&self, name: &str) -> Option<Color> {
+        self.option(name).color()
+    }
+
+    // 'smartcase' matches case once the pattern has a capital in it
+    fn ignore_case(&self, pattern: &str) -> bool {
+        self.option("ignorecase").is_on()
+            && !(self.option("smartcase").is_on() && pattern.chars().any(char::is_uppercase))
     }
 
     fn set_mode(&mut self, mode: Mode) {
         self.mode = mode;
-        Terminal::set_cursor_shape(mode.cursor_shape())
+        if mode == Mode::Command {
+            self.interactions.push_front(Interaction::Prompt {
+                kind: Prompt::Command,
+                text: String::new(),
+            });
+        }
     }
 
-    fn refresh_screen(&mut self) -> Result<(), io::Error> {
-        Terminal::cursor_hide();
-        Terminal::cursor_goto(&Pos::default());
+    // Draws the screen as it should look now
+    pub fn render(&mut self) -> Frame {
+        let mut frame = Frame::new(self.size);
+        frame.cursor_shape = self.mode.cursor_shape();
         if self.should_quit {
-            Terminal::clear_screen();
-            println!("Exiting rvim.\r");
-        } else {
-            self.document.highlight(
-                &self.highlighted_word,
-                Some(self.offset.y + self.terminal.size().height as usize),
-            );
-            self.draw_rows();
-            self.draw_status_bar();
-            self.draw_message_bar();
-            Terminal::cursor_goto(&Pos {
-                x: self.cursor_pos.x - self.offset.x,
-                y: self.cursor_pos.y - self.offset.y,
-            });
+            frame.print(0, 0, frame.width(), "Exiting rvim.", None, None);
+            frame.cursor = Some(Pos { x: 0, y: 1 });
+            return frame;
+        }
+        if let Some(Interaction::Pager { lines, top }) = self.interactions.front() {
+            self.draw_pager(&mut frame, lines, *top);
+            return frame;
         }
-        Terminal::cursor_show();
-        Terminal::flush()
+        self.store_window();
+        let area = self.screen_area();
+        let rects = self.layout.rects(area);
+        for (id, rect) in &rects {
+            let Some(index) = self.windows.iter().position(|w| w.id == *id) else {
+                continue;
+            };
+            let Some(buffer) = self
+                .buffers
+                .iter()
+                .position(|b| b.id == self.windows[index].buffer_id)
+            else {
+                continue;
+            };
+            // Windows that were not scrolled since the screen shrank or their
+            // options changed
+            let view = self.view(&self.buffers[buffer], &self.windows[index], rect);
+            let window = &mut self.windows[index];
+            let document = &mut self.buffers[buffer].document;
+            keep_in_view(&mut window.offset, window.cursor_pos, document, &view);
+            document.highlight(&self.highlighted_word, Some(window.offset.y + rect.height));
+        }
+        self.offset = self.windows[self.active].offset;
+        for (id, rect) in &rects {
+            if let Some(window) = self.windows.iter().find(|w| w.id == *id) {
+                self.draw_window(&mut frame, window, rect);
+            }
+        }
+        self.draw_separators(&mut frame, area);
+        self.draw_tab_line(&mut frame);
+        self.draw_message_bar(&mut frame, area.y + area.height);
+        let rect = self.window_rect();
+        let view = self.active_view();
+        let (x, y) = cursor_cell(self.offset, self.cursor_pos, self.document(), &view);
+        frame.cursor = Some(Pos {
+            x: rect.x + view.gutter + x,
+            y: rect.y + y,
+        });
+        frame
     }
 
-    fn draw_row(&self, row: &Row) {
-        let width = self.terminal.size().width as usize;
-        let start = self.offset.x;
-        let end = self.offset.x + width;
-        let row = row.render(start, end);
-        println!("{row}\r");
+    fn draw_window(&self, frame: &mut Frame, window: &Window, rect: &Rect) {
+        let Some(buffer) = self.buffers.iter().find(|b| b.id == window.buffer_id) else {
+            return;
+        };
+        let document = &buffer.document;
+        let view = self.view(buffer, window, rect);
+        let text_x = rect.x + view.gutter;
+        let number_color = self.color("linenrfg");
+        let height = rect.height.saturating_sub(1);
+        let mut index = window.offset.y;
+        let mut line = 0;
+        while line < height {
+            let y = rect.y + line;
+            let Some(row) = document.row(index) else {
+                if document.is_empty() && self.windows.len() == 1 && line == height / 3 {
+                    self.draw_welcome_message(frame, y, rect.width);
+                } else {
+                    frame.print(rect.x, y, rect.width, "~", None, None);
+                }
+                line += 1;
+                continue;
+            };
+            if view.gutter > 0 {
+                let number = format!("{:>width$} ", index + 1, width = view.gutter - 1);
+                frame.print(rect.x, y, view.gutter, &number, number_color, None);
+            }
+            if view.wrap {
+                // The rest of a line that does not fit is left out
+                for part in 0..row_height(row, &view).min(height - line) {
+                    let cells = row.render(part * view.width, view.width, view.tabstop);
+                    frame.print_cells(text_x, rect.y + line, view.width, cells);
+                    line += 1;
+                }
+            } else {
+                let cells = row.render(window.offset.x, view.width, view.tabstop);
+                frame.print_cells(text_x, y, view.width, cells);
+                line += 1;
+            }
+            index += 1;
+        }
+        let status_line = Rect {
+            y: rect.y + height,
+            height: 1,
+            ..*rect
+        };
+        let active = window.id == self.windows[self.active].id;
+        self.draw_status_bar(frame, document, window.cursor_pos, status_line, active);
     }
 
-    fn draw_rows(&self) {
-        let height = self.terminal.size().height;
-        for terminal_row in 0..height {
-            Terminal::clear_current_line();
-            if let Some(row) = self.document.row(terminal_row as usize + self.offset.y) {
-                self.draw_row(row)
-            } else if self.document.is_empty() && terminal_row == height / 3 {
-                self.draw_welcome_message();
+    fn draw_tab_line(&self, frame: &mut Frame) {
+        if self.tabs.len() < 2 {
+            return;
+        }
+        let width = frame.width();
+        let mut used = 0;
+        for (index, tab) in self.tabs.iter().enumerate() {
+            let buffer_id = if index == self.tab {
+                self.buffers[self.current].id
             } else {
-                println!("~\r");
+                tab.windows[tab.active].buffer_id
+            };
+            let Some(buffer) = self.buffers.iter().find(|b| b.id == buffer_id) else {
+                continue;
+            };
+            let modified = if buffer.document.is_dirty() { " +" } else { "" };
+            let label = format!(" {} {}{} ", index + 1, buffer.name(), modified);
+            let (fg, bg) = if index == self.tab {
+                (self.color("statusfg"), self.color("statusbg"))
+            } else {
+                (self.color("statusncfg"), self.color("statusncbg"))
+            };
+            used += frame.print(used, 0, width.saturating_sub(used), &label, fg, bg);
+        }
+        let rest = " ".repeat(width.saturating_sub(used));
+        let (fg, bg) = (self.color("statusncfg"), self.color("statusncbg"));
+        frame.print(used, 0, width, &rest, fg, bg);
+    }
+
+    fn draw_separators(&self, frame: &mut Frame, area: Rect) {
+        let (fg, bg) = (self.color("statusfg"), self.color("statusbg"));
+        for separator in self.layout.separators(area) {
+            for y in separator.y..separator.y + separator.height {
+                frame.set(separator.x, y, Cell::new("|", fg, bg));
             }
         }
     }
 
-    fn draw_welcome_message(&self) {
+    fn draw_welcome_message(&self, frame: &mut Frame, y: usize, width: usize) {
         let mut welcome_message = format!("RVim editor -- version {}", VERSION);
-        let width = self.terminal.size().width as usize;
         let len = welcome_message.len();
-        let padding = (width - len) / 2;
-        let spaces = " ".repeat(padding - 1);
+        let padding = width.saturating_sub(len) / 2;
+        let spaces = " ".repeat(padding.saturating_sub(1));
         welcome_message = format!("~{}{}", spaces, welcome_message);
-        welcome_message.truncate(width);
-        println!("{}\r", welcome_message);
+        frame.print(0, y, width, &welcome_message, None, None);
     }
 
-    fn draw_status_bar(&self) {
-        let width = self.terminal.size().width as usize;
-        let mode = format!("[{}]", self.mode.name());
-        let modified_indicator = if self.document.is_dirty() {
+    fn draw_status_bar(
+        &self,
+        frame: &mut Frame,
+        document: &Document,
+        cursor_pos: Pos,
+        line: Rect,
+        active: bool,
+    ) {
+        let mode = if active {
+            format!("[{}] ", self.mode.name())
+        } else {
+            String::new()
+        };
+        let modified_indicator = if document.is_dirty() {
             " (modified)"
         } else {
             ""
         };
-        let mut filename = self
-            .document
+        let mut filename = document
             .file_name
             .clone()
             .unwrap_or("[No Name]".to_string());
         filename.truncate(20);
+        if document.is_read_only() {
+            filename.push_str(" [RO]");
+        }
+        let loading_indicator = match (document.loading_progress(), document.load_error()) {
+            (Some(progress), _) => format!(" (loading {}%)", progress),
+            (None, Some(_)) => " [partly loaded]".to_string(),
+            (None, None) => String::new(),
+        };
         let file_status = format!(
-            "{} - {} lines{}",
+            "{} - {} lines{}{}",
             filename,
-            self.document.len(),
+            document.len(),
+            loading_indicator,
             modified_indicator
         );
+        let eol_indicator = if document.has_eol() { "" } else { " [noeol]" };
+        let format_indicator = if document.is_binary() {
+            "binary".to_string()
+        } else {
+            format!(
+                "{} | {}{}",
+                document.encoding().name(),
+                document.file_format().name(),
+                eol_indicator
+            )
+        };
         let line_indicator = format!(
-            "{} | {}:{}",
-            self.document.file_type(),
-            self.cursor_pos.x + 1,
-            self.cursor_pos.y + 1
+            "{} | {} | {}:{}",
+            document.file_type(),
+            format_indicator,
+            cursor_pos.x + 1,
+            cursor_pos.y + 1
         );
-        let mut status = format!("{mode} {file_status} {line_indicator}");
-        status = format!("{:width$}", status, width = width);
-        status.truncate(width);
+        let mut status = format!("{mode}{file_status} {line_indicator}");
+        status = format!("{:width$}", status, width = line.width);
+
+        let (fg, bg) = if active {
+            (self.color("statusfg"), self.color("statusbg"))
+        } else {
+            (self.color("statusncfg"), self.color("statusncbg"))
+        };
+        frame.print(line.x, line.y, line.width, &status, fg, bg);
+    }
 
-        Terminal::set_bg_color(STATUS_BG_COLOR);
-        Terminal::set_fg_color(STATUS_FG_COLOR);
-        println!("{status}\r");
-        Terminal::reset_fg_color();
-        Terminal::reset_bg_color();
+    fn draw_message_bar(&self, frame: &mut Frame, y: usize) {
+        let text = match self.interactions.front() {
+            Some(Interaction::Prompt { kind, text }) => format!("{}{}", kind.label(), text),
+            Some(Interaction::Question { text, .. }) => text.clone(),
+            _ if self.status_message.time.elapsed() < MESSAGE_TIMEOUT => {
+                self.status_message.text.clone()
+            }
+            _ => return,
+        };
+        frame.print(0, y, frame.width(), &text, None, None);
     }
 
-    fn draw_message_bar(&self) {
-        Terminal::clear_current_line();
-        let message = &self.status_message;
-        if Instant::now() - message.time < Duration::new(5, 0) {
-            let mut text = message.text.clone();
-            text.truncate(self.terminal.size().width as usize);
-            print!("{}", text);
+    fn draw_pager(&self, frame: &mut Frame, lines: &[String], top: usize) {
+        let height = self.pager_height();
+        let width = frame.width();
+        for (y, line) in lines.iter().skip(top).take(height).enumerate() {
+            let color = if line.starts_with("- ") {
+                self.color("diffdeletefg")
+            } else if line.starts_with("+ ") {
+                self.color("diffaddfg")
+            } else {
+                None
+            };
+            frame.print(0, y, width, line, color, None);
         }
+        for y in lines.len().saturating_sub(top)..height {
+            frame.print(0, y, width, "~", None, None);
+        }
+        let mut status = format!(
+            "{}-{} of {} lines (q to close)",
+            top + 1,
+            (top + height).min(lines.len()),
+            lines.len()
+        );
+        status = format!("{:width$}", status, width = width);
+        let (fg, bg) = (self.color("statusfg"), self.color("statusbg"));
+        frame.print(0, height, width, &status, fg, bg);
+        frame.cursor = Some(Pos {
+            x: 0,
+            y: height + 1,
+        });
+    }
+
+    // The pager leaves room for its status line and the message bar
+    fn pager_height(&self) -> usize {
+        (self.size.height as usize).saturating_sub(2)
     }
 
     fn move_cursor(&mut self, rel_pos: RelativePos) {
-        let height = self.document.len();
+        let height = self.document().len();
         let Pos { x: cur_x, y: cur_y } = self.cursor_pos;
         let RelativePos { x: _, y: rel_y } = rel_pos;
-        let width = self.document.row(cur_y).map(|r| r.len()).unwrap_or(0);
+        let width = self.document().row(cur_y).map(|r| r.len()).unwrap_or(0);
         let x;
         let y;
         if cur_x as isize + rel_pos.x < 0 {
-            y = cur_y.saturating_add_signed(rel_pos.y - 1).max(0);
-            x = self.document.row(y).map(|r| r.len()).unwrap_or(0);
+            y = cur_y.saturating_add_signed(rel_pos.y - 1);
+            x = self.document().row(y).map(|r| r.len()).unwrap_or(0);
         } else if cur_x.saturating_add_signed(rel_pos.x) > width {
             y = cur_y.saturating_add_signed(rel_y + 1).min(height);
             x = 0;
         } else {
-            y = cur_y.saturating_add_signed(rel_pos.y).max(0).min(height);
-            let width = self.document.row(y).map(|r| r.len()).unwrap_or(0);
-            x = cur_x.saturating_add_signed(rel_pos.x).max(0).min(width);
+            y = cur_y.saturating_add_signed(rel_pos.y).min(height);
+            let width = self.document().row(y).map(|r| r.len()).unwrap_or(0);
+            x = cur_x.saturating_add_signed(rel_pos.x).min(width);
         }
         self.cursor_pos = Pos { x, y };
     }
 
     fn scroll(&mut self) {
-        let Pos { x, y } = self.cursor_pos;
-        let width = self.terminal.size().width as usize;
-        let height = self.terminal.size().height as usize;
-        if x < self.offset.x {
-            self.offset.x = x;
-        } else if x >= self.offset.x + width {
-            self.offset.x = x - width + 1
-        }
+        let view = self.active_view();
+        let document = &self.buffers[self.current].document;
+        keep_in_view(&mut self.offset, self.cursor_pos, document, &view);
+    }
 
-        if y < self.offset.y {
-            self.offset.y = y;
-        } else if y >= self.offset.y + height {
-            self.offset.y = y - height + 1
+    fn handle_prompt_key(&mut self, mut kind: Prompt, mut text: String, key: Key) {
+        match key {
+            Key::Backspace if !text.is_empty() => {
+                text.pop();
+            }
+            Key::Char('\n') => {
+                return self.finish_prompt(kind, Some(text).filter(|t| !t.is_empty()))
+            }
+            Key::Char(c) if !c.is_control() => {
+                text.push(c);
+            }
+            Key::Esc => return self.finish_prompt(kind, None),
+            _ => (),
         }
+        if let Prompt::Search { direction, .. } = &mut kind {
+            self.search_as_you_type(key, &text, direction);
+        }
+        self.interactions
+            .push_front(Interaction::Prompt { kind, text });
     }
 
-    fn prompt<C>(&mut self, prompt: &str, mut callback: C) -> Result<Option<String>, io::Error>
-    where
-        C: FnMut(&mut Self, Key, &String),
-    {
-        let mut result = String::new();
-        loop {
-            self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
-            self.refresh_screen()?;
-            let key = Terminal::read_key()?;
-            match key {
-                Key::Backspace => {
-                    if !result.is_empty() {
-                        result.truncate(result.len() - 1);
-                    }
+    // `text` is None when the prompt was cancelled or left empty
+    fn finish_prompt(&mut self, kind: Prompt, text: Option<String>) {
+        self.status_message = StatusMessage::from(String::new());
+        match kind {
+            Prompt::Command => {
+                self.set_mode(Mode::Normal);
+                self.execute_command(text.unwrap_or_default().trim());
+            }
+            Prompt::Search { old_pos, .. } => {
+                if text.is_none() {
+                    self.cursor_pos = old_pos;
+                    self.scroll();
                 }
-                Key::Char('\n') => break,
-                Key::Char(c) => {
-                    if !c.is_control() {
-                        result.push(c);
-                    }
+                self.highlighted_word = None;
+            }
+            Prompt::SaveAs { force } => match text {
+                Some(file_name) => {
+                    self.document_mut().file_name = Some(file_name);
+                    self.save(force);
+                }
+                None => self.abort_save(),
+            },
+        }
+    }
+
+    fn save(&mut self, force: bool) {
+        if self.document().file_name.is_none() {
+            self.interactions.push_front(Interaction::Prompt {
+                kind: Prompt::SaveAs { force },
+                text: String::new(),
+            });
+            return;
+        }
+        if self.check_disk_change(Some(force)) {
+            self.write_document(force);
+        }
+    }
+
+    fn write_document(&mut self, force: bool) {
+        let backup = self.option("backup").is_on();
+        let saved = self.buffers[self.current]
+            .document
+            .save(backup, force)
+            .context("Could not write file");
+        match saved {
+            Ok(()) => {
+                self.status_message = StatusMessage::from("File saved successfully".to_string())
+            }
+            Err(error) => self.report(error),
+        }
+    }
+
+    // Whatever was to follow the save, like quitting after `:wq`, is dropped too
+    fn abort_save(&mut self) {
+        self.warn("Save aborted.".to_string());
+        self.deferred.clear();
+    }
+
+    // `:w file` names an unnamed buffer, otherwise it writes a copy
+    fn write_to(&mut self, file_name: &str, force: bool) {
+        if self.document().file_name.is_none() {
+            self.document_mut().file_name = Some(file_name.to_string());
+            return self.save(force);
+        }
+        if Path::new(file_name).exists() && !force {
+            self.fail(format!("File exists (add ! to override): {}", file_name));
+            return;
+        }
+        let backup = self.option("backup").is_on();
+        match self.buffers[self.current]
+            .document
+            .write_copy(file_name, backup)
+            .context("Could not write file")
+        {
+            Ok(()) => {
+                self.status_message = StatusMessage::from(format!("Written to {}", file_name))
+            }
+            Err(error) => self.report(error),
+        }
+    }
+
+    // Returns true when the file is unchanged on disk. Otherwise asks what to do,
+    // and a save given as `save` (its force flag) continues once answered.
+    fn check_disk_change(&mut self, save: Option<bool>) -> bool {
+        if !self.document().changed_on_disk() {
+            return true;
+        }
+        if save.is_none() && self.option("autoread").is_on() && !self.document().is_dirty() {
+            self.reload();
+            return true;
+        }
+        let asked = self.interactions.iter().any(|interaction| {
+            matches!(
+                interaction,
+                Interaction::Question {
+                    kind: Question::DiskChange { .. },
+                    ..
                 }
-                Key::Esc => {
-                    result.truncate(0);
-                    break;
+            )
+        });
+        if asked {
+            return false;
+        }
+        let text = if save.is_some() {
+            "[O]verwrite, [R]eload, [D]iff, [C]ancel? File changed on disk since it was read"
+        } else {
+            "[R]eload, [K]eep buffer, [D]iff? File changed on disk since it was read"
+        };
+        self.ask(Question::DiskChange { save }, text.to_string());
+        false
+    }
+
+    // Checks every loaded buffer, asking about the current one last so the
+    // question stays shown. Like Vim, other buffers are reloaded with
+    // 'autoread', or else warned about once.
+    fn check_all_disk_changes(&mut self) {
+        let autoread = self.option("autoread").is_on();
+        let mut changed = Vec::new();
+        let mut failed = None;
+        for (index, buffer) in self.buffers.iter_mut().enumerate() {
+            let name = buffer.name();
+            let document = &mut buffer.document;
+            if index == self.current || !document.changed_on_disk() {
+                continue;
+            }
+            if autoread && !document.is_dirty() {
+                match document
+                    .reload()
+                    .with_context(|| format!("Could not reload {}", name))
+                {
+                    Ok(()) => continue,
+                    Err(error) => failed = Some(error),
                 }
-                _ => (),
             }
-            callback(self, key, &result);
+            document.acknowledge_disk_change();
+            changed.push(name);
         }
-        self.status_message = StatusMessage::from(String::new());
-        if result.is_empty() {
-            return Ok(None);
+        if let Some(error) = failed {
+            self.report(error);
+        } else if !changed.is_empty() {
+            self.warn(format!(
+                "Changed on disk since it was read: {}",
+                changed.join(", ")
+            ));
+        }
+        self.check_disk_change(None);
+    }
+
+    fn answer_disk_change(&mut self, key: Key, save: Option<bool>) -> bool {
+        match (key, save) {
+            (Key::Char('r' | 'R'), _) => self.reload(),
+            (Key::Char('o' | 'O'), Some(force)) => self.write_document(force),
+            (Key::Char('k' | 'K') | Key::Esc, None) => {
+                self.document_mut().acknowledge_disk_change()
+            }
+            (Key::Char('c' | 'C') | Key::Esc, Some(_)) => self.abort_save(),
+            (Key::Char('d' | 'D'), _) => {
+                self.show_disk_diff();
+                return false;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn reload(&mut self) {
+        match self
+            .document_mut()
+            .reload()
+            .context("Could not reload file")
+        {
+            Ok(()) => {
+                self.status_message =
+                    StatusMessage::from("File changed on disk, reloaded".to_string())
+            }
+            Err(error) => self.report(error),
         }
-        Ok(Some(result))
+        self.clamp_cursor();
+        self.scroll();
     }
 
-    fn save(&mut self) {
-        if self.document.file_name.is_none() {
-            let new_name = self.prompt("Save as: ", |_, _, _| {}).unwrap_or(None);
+    // Keeps the cursor inside the document after it shrank, e.g. in another window
+    fn clamp_cursor(&mut self) {
+        self.cursor_pos.y = self.cursor_pos.y.min(self.document().len());
+        let width = self.document().row(self.cursor_pos.y).map_or(0, Row::len);
+        self.cursor_pos.x = self.cursor_pos.x.min(width);
+    }
 
-            if new_name.is_none() {
-                self.status_message = StatusMessage::from("Save aborted.".to_string());
+    fn show_disk_diff(&mut self) {
+        let file_name = self.document().file_name.clone().unwrap_or_default();
+        // Large files load in the background, and the diff needs all of both
+        if let Err(error) = self
+            .document_mut()
+            .finish_loading()
+            .context("Could not finish loading file")
+        {
+            return self.report(error);
+        }
+        let on_disk = Document::open(&file_name).and_then(|mut document| {
+            document.finish_loading()?;
+            Ok(document)
+        });
+        let on_disk = match on_disk {
+            Ok(document) => document,
+            Err(error) => {
+                self.fail(format!("Could not read {}: {}", file_name, error));
                 return;
-            } else {
-                self.document.file_name = new_name;
             }
-        }
-        let message = if self.document.save().is_ok() {
-            "File saved successfully"
-        } else {
-            "Error writing to file!"
         };
-        self.status_message = StatusMessage::from(message.to_string());
+        let old: Vec<&str> = on_disk.lines().collect();
+        let new: Vec<&str> = self.document().lines().collect();
+        let mut lines = vec![
+            format!("--- {} (on disk)", file_name),
+            "+++ buffer".to_string(),
+        ];
+        lines.extend(diff::unified(&old, &new));
+        self.show_lines(lines);
     }
 
-    fn search(&mut self) {
-        let old_pos = self.cursor_pos;
-        let mut direction = Direction::Forward;
-        let query = self
-            .prompt(
-                "Search (ESC to caecel, Arrows to navigate): ",
-                |editor, key, query| {
-                    let mut moved = false;
-                    match key {
-                        Key::Right | Key::Down => {
-                            direction = Direction::Forward;
-                            editor.move_cursor(RelativePos { x: 1, y: 0 });
-                            moved = true;
-                        }
-                        Key::Left | Key::Up => direction = Direction::Backward,
-                        _ => direction = Direction::Forward,
+    // Runs `command` in the background, showing its output as it comes and
+    // all of it in the pager once it exits. Headless runs wait for it instead.
+    fn shell_command(&mut self, command: &str) {
+        if command.is_empty() {
+            self.fail("Argument required");
+            return;
+        }
+        if self.headless {
+            match job::run(command) {
+                Ok((output, code)) => {
+                    self.show_lines(output);
+                    self.status_message = StatusMessage::from(shell_exit_message(code));
+                }
+                Err(error) => self.fail(format!("Could not run {}: {}", command, error)),
+            }
+            return;
+        }
+        let id = self.next_job_id;
+        match job::spawn(id, command, self.job_events.0.clone(), self.waker.clone()) {
+            Ok(job) => {
+                self.next_job_id += 1;
+                self.jobs.push(job);
+                self.status_message = StatusMessage::from(format!("Running !{}", command));
+            }
+            Err(error) => self.fail(format!("Could not run {}: {}", command, error)),
+        }
+    }
+
+    fn handle_job_events(&mut self) {
+        while let Ok(event) = self.job_events.1.try_recv() {
+            match event {
+                JobEvent::Output { id, line } => {
+                    if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+                        self.status_message =
+                            StatusMessage::from(format!("!{}: {}", job.command, line));
+                        job.output.push(line);
                     }
-                    if let Some(pos) = editor.document.find(query, &editor.cursor_pos, direction) {
-                        editor.cursor_pos = pos;
-                        editor.scroll();
-                    } else if moved {
-                        editor.move_cursor(RelativePos { x: -1, y: 0 });
+                }
+                JobEvent::Exited { id, code } => {
+                    let Some(index) = self.jobs.iter().position(|job| job.id == id) else {
+                        continue;
+                    };
+                    let job = self.jobs.remove(index);
+                    let message = format!("!{}: {}", job.command, shell_exit_message(code));
+                    if !job.output.is_empty() {
+                        let mut lines = vec![format!(":!{}", job.command)];
+                        lines.extend(job.output);
+                        lines.push(String::new());
+                        lines.push(message.clone());
+                        self.show_lines(lines);
                     }
-                    editor.highlighted_word = Some(query.to_string());
-                },
-            )
-            .unwrap_or(None);
-        if query.is_none() {
-            self.cursor_pos = old_pos;
+                    self.status_message = StatusMessage::from(message);
+                }
+            }
+        }
+    }
+
+    // A minimal pager: arrows or j/k scroll, q or <Esc> closes it. Headless runs
+    // print the lines instead.
+    fn show_lines(&mut self, lines: Vec<String>) {
+        if self.headless {
+            for line in lines {
+                println!("{}", line);
+            }
+            return;
+        }
+        self.interactions
+            .push_front(Interaction::Pager { lines, top: 0 });
+    }
+
+    fn handle_pager_key(&mut self, lines: Vec<String>, mut top: usize, key: Key) {
+        let height = self.pager_height();
+        let last_top = lines.len().saturating_sub(height);
+        match key {
+            Key::Char('q') | Key::Esc => return,
+            Key::Char('j') | Key::Down => top = (top + 1).min(last_top),
+            Key::Char('k') | Key::Up => top = top.saturating_sub(1),
+            Key::Char(' ') | Key::PageDown => top = (top + height).min(last_top),
+            Key::PageUp => top = top.saturating_sub(height),
+            _ => (),
+        }
+        self.interactions
+            .push_front(Interaction::Pager { lines, top });
+    }
+
+    fn search(&mut self) {
+        self.interactions.push_front(Interaction::Prompt {
+            kind: Prompt::Search {
+                old_pos: self.cursor_pos,
+                direction: Direction::Forward,
+            },
+            text: String::new(),
+        });
+    }
+
+    // Arrows move between matches while the query is typed
+    fn search_as_you_type(&mut self, key: Key, query: &str, direction: &mut Direction) {
+        let mut moved = false;
+        match key {
+            Key::Right | Key::Down => {
+                *direction = Direction::Forward;
+                self.move_cursor(RelativePos { x: 1, y: 0 });
+                moved = true;
+            }
+            Key::Left | Key::Up => *direction = Direction::Backward,
+            _ => *direction = Direction::Forward,
+        }
+        let ignore_case = self.ignore_case(query);
+        if let Some(pos) = self
+            .document()
+            .find(query, &self.cursor_pos, *direction, ignore_case)
+        {
+            self.cursor_pos = pos;
             self.scroll();
+        } else if moved {
+            self.move_cursor(RelativePos { x: -1, y: 0 });
         }
-        self.highlighted_word = None;
+        self.highlighted_word = Some(query.to_string());
     }
 
     fn quit(&mut self) {
-        if self.document.is_dirty() {
-            self.status_message = StatusMessage::from(
+        if self.document().is_dirty() {
+            self.warn(
                 "WARNING! File has unsaved changes. Please use <C-X> to abort changes".to_string(),
             );
+        } else if let Some(buffer) = self.buffers.iter().find(|b| b.document.is_dirty()) {
+            let message = format!(
+                "WARNING! Buffer {} \"{}\" has unsaved changes. Please use <C-X> to abort changes",
+                buffer.id,
+                buffer.name()
+            );
+            self.warn(message);
         } else {
             self.should_quit = true;
         }
     }
 
     fn insert(&mut self, c: char) {
-        self.document.insert(&self.cursor_pos, c);
-        self.move_cursor(RelativePos { x: 1, y: 0 })
+        let cursor_pos = self.cursor_pos;
+        if self.document().is_binary() {
+            match self.document_mut().overwrite_byte(&cursor_pos, c) {
+                Ok(Some(pos)) => self.cursor_pos = pos,
+                Ok(None) => {
+                    self.status_message = StatusMessage::from(BINARY_EDIT_MESSAGE.to_string())
+                }
+                Err(error) => self.fail(error.to_string()),
+            }
+            return;
+        }
+        if c == '\t' && self.option("expandtab").is_on() {
+            return self.insert_tab_spaces();
+        }
+        match self.document_mut().insert(&cursor_pos, c) {
+            Ok(()) => self.move_cursor(RelativePos { x: 1, y: 0 }),
+            Err(error) => self.fail(error.to_string()),
+        }
     }
 
-    fn delete(&mut self, direction: Direction) {
-        match direction {
-            Direction::Backward => {
-                if self.cursor_pos.x > 0 || self.cursor_pos.y > 0 {
-                    self.move_cursor(RelativePos { x: -1, y: 0 });
-                    self.document.delete(&self.cursor_pos);
-                }
+    // Spaces up to the next multiple of 'shiftwidth', or of 'tabstop' when that
+    // is 0
+    fn insert_tab_spaces(&mut self) {
+        let width = match self.option("shiftwidth").number() {
+            0 => self.option("tabstop").number().max(1),
+            width => width,
+        };
+        let x = self.document().row(self.cursor_pos.y).map_or(0, |row| {
+            row.display_x(self.cursor_pos.x, self.option("tabstop").number())
+        });
+        for _ in 0..width - x % width {
+            let cursor_pos = self.cursor_pos;
+            if let Err(error) = self.document_mut().insert(&cursor_pos, ' ') {
+                self.fail(error.to_string());
+                return;
             }
-            Direction::Forward => {
-                self.document.delete(&self.cursor_pos);
+            self.move_cursor(RelativePos { x: 1, y: 0 });
+        }
+    }
+
+    fn delete(&mut self, direction: Direction) {
+        if self.document().is_binary() {
+            self.status_message = StatusMessage::from(BINARY_EDIT_MESSAGE.to_string());
+            return;
+        }
+        let old_pos = self.cursor_pos;
+        if direction == Direction::Backward {
+            if old_pos.x == 0 && old_pos.y == 0 {
+                return;
             }
+            self.move_cursor(RelativePos { x: -1, y: 0 });
+        }
+        let cursor_pos = self.cursor_pos;
+        if let Err(error) = self.document_mut().delete(&cursor_pos) {
+            self.cursor_pos = old_pos;
+            self.fail(error.to_string());
+        }
+    }
+}
+
+// Moves `offset` so that `cursor` is inside the view, with 'scrolloff' lines
+// around it where the document has them. `offset.x` is a screen column.
+fn keep_in_view(offset: &mut Pos, cursor: Pos, document: &Document, view: &View) {
+    let above = view.scrolloff.min(view.height.saturating_sub(1) / 2);
+    let below = above.min(document.len().saturating_sub(cursor.y + 1));
+    if cursor.y < offset.y + above {
+        offset.y = cursor.y.saturating_sub(above);
+    }
+    if view.wrap {
+        offset.x = 0;
+        // Drops lines from the top until the cursor line and the ones below it
+        // fit, where the cursor may be just past the end of its line
+        let height = |index| document.row(index).map_or(1, |row| row_height(row, view));
+        let (_, y) = cursor_cell(*offset, cursor, document, view);
+        let above: usize = (offset.y..cursor.y).map(height).sum();
+        let mut needed = (y + 1).max(above + height(cursor.y));
+        needed += (cursor.y + 1..=cursor.y + below).map(height).sum::<usize>();
+        while needed > view.height && offset.y < cursor.y {
+            needed -= height(offset.y);
+            offset.y += 1;
         }
+        return;
+    }
+    if cursor.y + below >= offset.y + view.height {
+        offset.y = cursor.y + below + 1 - view.height;
+    }
+    let x = document
+        .row(cursor.y)
+        .map_or(0, |row| row.display_x(cursor.x, view.tabstop));
+    if x < offset.x {
+        offset.x = x;
+    } else if x >= offset.x + view.width {
+        offset.x = x - view.width + 1
+    }
+}
+
+// Where `cursor` is shown in a text area scrolled to `offset`
+fn cursor_cell(offset: Pos, cursor: Pos, document: &Document, view: &View) -> (usize, usize) {
+    let x = document
+        .row(cursor.y)
+        .map_or(0, |row| row.display_x(cursor.x, view.tabstop));
+    if !view.wrap {
+        return (
+            x.saturating_sub(offset.x),
+            cursor.y.saturating_sub(offset.y),
+        );
+    }
+    let above: usize = (offset.y..cursor.y)
+        .map(|index| document.row(index).map_or(1, |row| row_height(row, view)))
+        .sum();
+    (x % view.width, above + x / view.width)
+}
+
+// The screen lines a wrapped row takes
+fn row_height(row: &Row, view: &View) -> usize {
+    ((row.display_width(view.tabstop) + view.width - 1) / view.width).max(1)
+}
+
+fn shell_exit_message(code: Option<i32>) -> String {
+    match code {
+        Some(0) => "done".to_string(),
+        Some(code) => format!("shell returned {}", code),
+        None => "killed by a signal".to_string(),
     }
 }
 
-fn die(e: &std::io::Error) {
-    Terminal::clear_screen();
-    panic!("{}", e);
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown error".to_string()
+    }
 }
@@ -0,0 +1,165 @@
+use std::io::{Error, ErrorKind};
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl Encoding {
+    // Latin-1 maps every byte to a char, so it is the lossless fallback for
+    // files that are not valid in any other encoding.
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(UTF8_BOM) {
+            Self::Utf8Bom
+        } else if bytes.starts_with(UTF16LE_BOM) && bytes.len() % 2 == 0 {
+            Self::Utf16Le
+        } else if bytes.starts_with(UTF16BE_BOM) && bytes.len() % 2 == 0 {
+            Self::Utf16Be
+        } else if is_utf8(bytes) {
+            Self::Utf8
+        } else {
+            Self::Latin1
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Self::Utf8),
+            "utf-8-bom" | "utf8-bom" => Some(Self::Utf8Bom),
+            "utf-16le" | "utf16le" => Some(Self::Utf16Le),
+            "utf-16be" | "utf16be" | "utf-16" | "utf16" => Some(Self::Utf16Be),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Self::Latin1),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf8Bom => "utf-8-bom",
+            Self::Utf16Le => "utf-16le",
+            Self::Utf16Be => "utf-16be",
+            Self::Latin1 => "latin1",
+        }
+        .to_string()
+    }
+
+    pub fn bom(self) -> &'static [u8] {
+        match self {
+            Self::Utf8Bom => UTF8_BOM,
+            Self::Utf16Le => UTF16LE_BOM,
+            Self::Utf16Be => UTF16BE_BOM,
+            Self::Utf8 | Self::Latin1 => &[],
+        }
+    }
+
+    // Whether text in this encoding can be split into lines on single bytes
+    pub fn is_byte_oriented(self) -> bool {
+        !matches!(self, Self::Utf16Le | Self::Utf16Be)
+    }
+
+    // Decodes text that no longer carries the byte order mark
+    pub fn decode(self, bytes: &[u8]) -> Result<String, Error> {
+        match self {
+            Self::Utf8 | Self::Utf8Bom => String::from_utf8(bytes.to_vec())
+                .map_err(|error| Error::new(ErrorKind::InvalidData, error)),
+            Self::Utf16Le | Self::Utf16Be => {
+                let units: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|pair| {
+                        if self == Self::Utf16Le {
+                            u16::from_le_bytes([pair[0], pair[1]])
+                        } else {
+                            u16::from_be_bytes([pair[0], pair[1]])
+                        }
+                    })
+                    .collect();
+                String::from_utf16(&units)
+                    .map_err(|error| Error::new(ErrorKind::InvalidData, error))
+            }
+            Self::Latin1 => Ok(bytes.iter().map(|b| *b as char).collect()),
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Utf8 | Self::Utf8Bom => Ok(text.as_bytes().to_vec()),
+            Self::Utf16Le => Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Self::Utf16Be => Ok(text.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+            Self::Latin1 => text
+                .chars()
+                .map(|c| {
+                    u8::try_from(c).map_err(|_| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("'{}' cannot be converted to latin1", c),
+                        )
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+// A sequence cut off at the end of the sample still counts as UTF-8
+fn is_utf8(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(error) => error.error_len().is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_byte_order_marks() {
+        assert_eq!(Encoding::detect(b"\xEF\xBB\xBFabc"), Encoding::Utf8Bom);
+        assert_eq!(Encoding::detect(b"\xFF\xFEa\x00"), Encoding::Utf16Le);
+        assert_eq!(Encoding::detect(b"\xFE\xFF\x00a"), Encoding::Utf16Be);
+        // An odd length cannot be UTF-16
+        assert_eq!(Encoding::detect(b"\xFF\xFEa"), Encoding::Latin1);
+    }
+
+    #[test]
+    fn detects_utf8_cut_off_at_the_end_of_the_sample() {
+        assert_eq!(Encoding::detect("café".as_bytes()), Encoding::Utf8);
+        assert_eq!(Encoding::detect(b"caf\xC3"), Encoding::Utf8);
+        assert_eq!(Encoding::detect(b"caf\xE9 au lait"), Encoding::Latin1);
+    }
+
+    #[test]
+    fn round_trips_utf16() {
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be] {
+            let bytes = encoding.encode("héllo €").unwrap();
+            assert_eq!(encoding.decode(&bytes).unwrap(), "héllo €");
+        }
+        assert!(Encoding::Utf16Le.decode(&[0x00, 0xD8]).is_err());
+    }
+
+    #[test]
+    fn latin1_maps_every_byte() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let text = Encoding::Latin1.decode(&bytes).unwrap();
+        assert_eq!(Encoding::Latin1.encode(&text).unwrap(), bytes);
+        assert!(Encoding::Latin1.encode("€").is_err());
+    }
+
+    #[test]
+    fn reads_names() {
+        assert_eq!(Encoding::from_name("UTF-16"), Some(Encoding::Utf16Be));
+        assert_eq!(Encoding::from_name("latin-1"), Some(Encoding::Latin1));
+        assert_eq!(Encoding::from_name("ebcdic"), None);
+        assert_eq!(Encoding::Utf8Bom.name(), "utf-8-bom");
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Error {
+    // `context` says what was being done, e.g. "Could not write swap file"
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    // A configuration file that could not be parsed; `position` is its line
+    // and column, counted from 1
+    Config {
+        path: PathBuf,
+        position: Option<(usize, usize)>,
+        message: String,
+    },
+    // An Ex command or key that could not be carried out, e.g. an unknown command
+    Command(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn config(path: PathBuf, text: &str, error: &toml::de::Error) -> Self {
+        let offset = error.span().map(|span| span.start);
+        Self::config_at(path, text, offset, error.message())
+    }
+
+    // An error about what starts at byte `offset` of the file's `text`
+    pub fn config_at(path: PathBuf, text: &str, offset: Option<usize>, message: &str) -> Self {
+        let position = offset.map(|offset| {
+            let before = &text[..offset.min(text.len())];
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            (line, column)
+        });
+        Self::Config {
+            path,
+            position,
+            message: message.trim_end().replace('\n', " "),
+        }
+    }
+
+    // Like the exit codes of sysexits.h
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io { .. } => 74,
+            Self::Config { .. } => 78,
+            Self::Command(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io { context, source } => write!(f, "{}: {}", context, source),
+            Self::Config {
+                path,
+                position: Some((line, column)),
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            Self::Config { path, message, .. } => write!(f, "{}: {}", path.display(), message),
+            Self::Command(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Config { .. } | Self::Command(_) => None,
+        }
+    }
+}
+
+// Adds what was being done to an I/O error
+pub trait Context<T> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T>;
+
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+}
+
+impl<T> Context<T> for std::result::Result<T, io::Error> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context.into(),
+            source,
+        })
+    }
+
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context().into(),
+            source,
+        })
+    }
+}
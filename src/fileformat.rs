@@ -0,0 +1,118 @@
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FileFormat {
+    #[default]
+    Unix,
+    Dos,
+    Mac,
+}
+
+impl FileFormat {
+    // Dos only when every line feed is preceded by a carriage return, so files
+    // with mixed endings keep their stray `\r` as row content and round-trip.
+    pub fn detect(sample: &[u8]) -> Self {
+        let mut line_feeds = 0;
+        let mut crlf = 0;
+        for (index, byte) in sample.iter().enumerate() {
+            if *byte == b'\n' {
+                line_feeds += 1;
+                if index > 0 && sample[index - 1] == b'\r' {
+                    crlf += 1;
+                }
+            }
+        }
+        if line_feeds > 0 && line_feeds == crlf {
+            Self::Dos
+        } else if line_feeds == 0 && sample.contains(&b'\r') {
+            Self::Mac
+        } else {
+            Self::Unix
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(Self::Unix),
+            "dos" => Some(Self::Dos),
+            "mac" => Some(Self::Mac),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Self::Unix => "unix",
+            Self::Dos => "dos",
+            Self::Mac => "mac",
+        }
+        .to_string()
+    }
+
+    pub fn line_ending(self) -> &'static str {
+        match self {
+            Self::Unix => "\n",
+            Self::Dos => "\r\n",
+            Self::Mac => "\r",
+        }
+    }
+
+    // Returns the lines of `contents` and whether the last one was terminated
+    pub fn split(self, contents: &str) -> (Vec<&str>, bool) {
+        if contents.is_empty() {
+            return (Vec::new(), true);
+        }
+        let ending = self.line_ending();
+        let mut lines: Vec<&str> = contents.split(ending).collect();
+        let eol = contents.ends_with(ending);
+        if eol {
+            lines.pop();
+        }
+        (lines, eol)
+    }
+
+    // Strips the line ending from a line read up to and including its terminator
+    pub fn strip(self, line: &str) -> (&str, bool) {
+        match line.strip_suffix(self.line_ending()) {
+            Some(stripped) => (stripped, true),
+            None => (line, false),
+        }
+    }
+
+    pub fn terminator(self) -> u8 {
+        match self {
+            Self::Unix | Self::Dos => b'\n',
+            Self::Mac => b'\r',
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_line_endings() {
+        assert_eq!(FileFormat::detect(b"a\r\nb\r\n"), FileFormat::Dos);
+        assert_eq!(FileFormat::detect(b"a\nb\n"), FileFormat::Unix);
+        assert_eq!(FileFormat::detect(b"a\r\nb\n"), FileFormat::Unix);
+        assert_eq!(FileFormat::detect(b"a\rb\r"), FileFormat::Mac);
+        assert_eq!(FileFormat::detect(b""), FileFormat::Unix);
+    }
+
+    #[test]
+    fn splits_lines() {
+        assert_eq!(FileFormat::Dos.split("a\r\nb\r\n"), (vec!["a", "b"], true));
+        assert_eq!(FileFormat::Unix.split("a\nb"), (vec!["a", "b"], false));
+        assert_eq!(FileFormat::Unix.split(""), (vec![], true));
+        // Stray endings of another format stay in the row
+        assert_eq!(FileFormat::Dos.split("a\nb\r\n"), (vec!["a\nb"], true));
+        assert_eq!(FileFormat::Unix.split("a\r\n"), (vec!["a\r"], true));
+    }
+
+    #[test]
+    fn strips_line_endings() {
+        assert_eq!(FileFormat::Dos.strip("a\r\n"), ("a", true));
+        assert_eq!(FileFormat::Dos.strip("a\n"), ("a\n", false));
+        assert_eq!(FileFormat::Mac.strip("a\r"), ("a", true));
+        assert_eq!(FileFormat::Unix.strip("a"), ("a", false));
+    }
+}
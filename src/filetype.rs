@@ -16,7 +16,7 @@ impl Default for FileType {
 
 impl FileType {
     pub fn from(file_name: &str) -> Self {
-        let file_ext = file_name.split('.').last().unwrap_or("plain");
+        let file_ext = file_name.split('.').next_back().unwrap_or("plain");
         let name = match file_ext {
             "rs" => "rust",
             _ => "plain",
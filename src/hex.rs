@@ -0,0 +1,129 @@
+use crate::{Pos, Row};
+
+pub const BYTES_PER_ROW: usize = 16;
+const OFFSET_WIDTH: usize = 10;
+const ASCII_START: usize = OFFSET_WIDTH + BYTES_PER_ROW * 3 + 1;
+const BINARY_SAMPLE_SIZE: usize = 8192;
+
+pub fn is_binary(sample: &[u8]) -> bool {
+    sample[..sample.len().min(BINARY_SAMPLE_SIZE)].contains(&0)
+}
+
+// Raw bytes of a binary file, rendered as `offset  hex bytes  |ascii|` rows.
+// Bytes can be overwritten in place but never inserted or removed.
+pub struct HexBuffer {
+    bytes: Vec<u8>,
+}
+
+enum Column {
+    Nibble { byte: usize, high: bool },
+    Ascii { byte: usize },
+}
+
+impl From<Vec<u8>> for HexBuffer {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+}
+
+impl HexBuffer {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn rows(&self) -> Vec<Row> {
+        (0..(self.bytes.len() + BYTES_PER_ROW - 1) / BYTES_PER_ROW)
+            .map(|index| self.row(index))
+            .collect()
+    }
+
+    pub fn row(&self, index: usize) -> Row {
+        let start = index * BYTES_PER_ROW;
+        let end = (start + BYTES_PER_ROW).min(self.bytes.len());
+        let bytes = &self.bytes[start..end];
+        let mut row = format!("{:08x}  ", start);
+        for column in 0..BYTES_PER_ROW {
+            match bytes.get(column) {
+                Some(byte) => row.push_str(&format!("{:02x} ", byte)),
+                None => row.push_str("   "),
+            }
+        }
+        row.push('|');
+        for byte in bytes {
+            if byte.is_ascii_graphic() || *byte == b' ' {
+                row.push(*byte as char);
+            } else {
+                row.push('.');
+            }
+        }
+        row.push('|');
+        Row::from(row.as_str())
+    }
+
+    // Overwrites the byte under the cursor and returns where the cursor goes next
+    pub fn overwrite(&mut self, at: &Pos, c: char) -> Option<Pos> {
+        let row_start = at.y * BYTES_PER_ROW;
+        match column_at(at.x)? {
+            Column::Nibble { byte, high } => {
+                let value = c.to_digit(16)? as u8;
+                let current = self.bytes.get_mut(row_start + byte)?;
+                if high {
+                    *current = (*current & 0x0f) | (value << 4);
+                    return Some(Pos {
+                        x: at.x + 1,
+                        y: at.y,
+                    });
+                }
+                *current = (*current & 0xf0) | value;
+                Some(self.next_byte(at, byte, OFFSET_WIDTH + (byte + 1) * 3, OFFSET_WIDTH))
+            }
+            Column::Ascii { byte } => {
+                if !c.is_ascii() {
+                    return None;
+                }
+                *self.bytes.get_mut(row_start + byte)? = c as u8;
+                Some(self.next_byte(at, byte, at.x + 1, ASCII_START))
+            }
+        }
+    }
+
+    fn next_byte(&self, at: &Pos, byte: usize, same_row_x: usize, next_row_x: usize) -> Pos {
+        let next_index = at.y * BYTES_PER_ROW + byte + 1;
+        if next_index >= self.bytes.len() {
+            *at
+        } else if byte + 1 < BYTES_PER_ROW {
+            Pos {
+                x: same_row_x,
+                y: at.y,
+            }
+        } else {
+            Pos {
+                x: next_row_x,
+                y: at.y + 1,
+            }
+        }
+    }
+}
+
+fn column_at(x: usize) -> Option<Column> {
+    if (OFFSET_WIDTH..ASCII_START - 1).contains(&x) {
+        let relative = x - OFFSET_WIDTH;
+        match relative % 3 {
+            0 => Some(Column::Nibble {
+                byte: relative / 3,
+                high: true,
+            }),
+            1 => Some(Column::Nibble {
+                byte: relative / 3,
+                high: false,
+            }),
+            _ => None,
+        }
+    } else if (ASCII_START..ASCII_START + BYTES_PER_ROW).contains(&x) {
+        Some(Column::Ascii {
+            byte: x - ASCII_START,
+        })
+    } else {
+        None
+    }
+}
@@ -1,4 +1,4 @@
-use termion::color;
+use crate::Color;
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum Type {
@@ -55,16 +55,16 @@ impl Options {
 }
 
 impl Type {
-    pub fn to_color(self) -> impl color::Color {
+    pub fn to_color(self) -> Color {
         match self {
-            Type::Number => color::Rgb(220, 163, 163),
-            Type::Match => color::Rgb(38, 139, 210),
-            Type::String => color::Rgb(211, 54, 130),
-            Type::Character => color::Rgb(108, 113, 196),
-            Type::MultilineComment | Type::Comment => color::Rgb(133, 153, 0),
-            Type::PrimaryKeywords => color::Rgb(181, 137, 0),
-            Type::SecondaryKeywords => color::Rgb(42, 161, 152),
-            _ => color::Rgb(255, 255, 255),
+            Type::Number => Color(220, 163, 163),
+            Type::Match => Color(38, 139, 210),
+            Type::String => Color(211, 54, 130),
+            Type::Character => Color(108, 113, 196),
+            Type::MultilineComment | Type::Comment => Color(133, 153, 0),
+            Type::PrimaryKeywords => Color(181, 137, 0),
+            Type::SecondaryKeywords => Color(42, 161, 152),
+            _ => Color(255, 255, 255),
         }
     }
 }
@@ -0,0 +1,79 @@
+use crate::Waker;
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+// A shell command running in the background, see `spawn`
+pub struct Job {
+    pub id: usize,
+    pub command: String,
+    pub output: Vec<String>,
+}
+
+pub enum JobEvent {
+    Output { id: usize, line: String },
+    Exited { id: usize, code: Option<i32> },
+}
+
+// Starts `command` in the shell with its stderr merged into its stdout. A
+// thread sends each line it prints and then its exit code to `events`, waking
+// `waker` after every event.
+pub fn spawn(
+    id: usize,
+    command: &str,
+    events: Sender<JobEvent>,
+    waker: Option<Waker>,
+) -> Result<Job, io::Error> {
+    let mut child = shell(command)?;
+    let stdout = child.stdout.take();
+    thread::spawn(move || {
+        let send = |event| {
+            let _ = events.send(event);
+            if let Some(waker) = &waker {
+                waker.wake();
+            }
+        };
+        if let Some(stdout) = stdout {
+            for line in lines(stdout) {
+                send(JobEvent::Output { id, line });
+            }
+        }
+        let code = child.wait().ok().and_then(|status| status.code());
+        send(JobEvent::Exited { id, code });
+    });
+    Ok(Job {
+        id,
+        command: command.to_string(),
+        output: Vec::new(),
+    })
+}
+
+// Runs `command` to completion, returning its output lines and exit code
+pub fn run(command: &str) -> Result<(Vec<String>, Option<i32>), io::Error> {
+    let mut child = shell(command)?;
+    let output = child
+        .stdout
+        .take()
+        .map(|stdout| lines(stdout).collect())
+        .unwrap_or_default();
+    let code = child.wait()?.code();
+    Ok((output, code))
+}
+
+fn shell(command: &str) -> Result<Child, io::Error> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("exec 2>&1\n{}", command))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+}
+
+// Output that is not UTF-8 is shown as well as it can be rather than cut short
+fn lines<R: Read>(output: R) -> impl Iterator<Item = String> {
+    BufReader::new(output)
+        .split(b'\n')
+        .map_while(Result::ok)
+        .map(|line| String::from_utf8_lossy(&line).into_owned())
+}
@@ -0,0 +1,298 @@
+// A key as the editor sees it, whatever the terminal sent for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Char(char),
+    Ctrl(char),
+    Alt(char),
+    F(u8),
+    Esc,
+    Backspace,
+    Delete,
+    Insert,
+    BackTab,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+}
+
+// The keys `<leader>` and `<localleader>` stand for in mappings, from the
+// options of the same names
+#[derive(Clone, Copy, Debug)]
+pub struct Leaders {
+    pub leader: Key,
+    pub local: Key,
+}
+
+impl Default for Leaders {
+    // Like Vim, a backslash
+    fn default() -> Self {
+        Self {
+            leader: Key::Char('\\'),
+            local: Key::Char('\\'),
+        }
+    }
+}
+
+impl Leaders {
+    fn named(&self, name: &str) -> Option<Key> {
+        match name.to_ascii_lowercase().as_str() {
+            "leader" => Some(self.leader),
+            "localleader" => Some(self.local),
+            _ => None,
+        }
+    }
+
+    // Like `from_name`, also reading `leader` and `localleader`
+    pub fn key(&self, name: &str) -> Option<Key> {
+        self.named(name).or_else(|| from_name(name))
+    }
+
+    // Like `parse`, also reading `<leader>` and `<localleader>`
+    pub fn parse(&self, notation: &str) -> Vec<Key> {
+        parse_with(notation, |name| {
+            self.named(name).or_else(|| named_key(name))
+        })
+    }
+}
+
+// Parses Vim style key notation such as `ihello<Esc>:w<CR>` or `<C-w>l`.
+// Unknown `<...>` names are taken literally, like Vim does.
+pub fn parse(notation: &str) -> Vec<Key> {
+    parse_with(notation, named_key)
+}
+
+fn parse_with<F: Fn(&str) -> Option<Key>>(notation: &str, named_key: F) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut rest = notation;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some((key, len)) = rest[1..].find('>').and_then(|end| {
+                let name = &rest[1..end + 1];
+                named_key(name).map(|key| (key, end + 2))
+            }) {
+                keys.push(key);
+                rest = &rest[len..];
+                continue;
+            }
+        }
+        keys.push(Key::Char(c));
+        rest = &rest[c.len_utf8()..];
+    }
+    keys
+}
+
+fn named_key(name: &str) -> Option<Key> {
+    let lower = name.to_ascii_lowercase();
+    let key = match lower.as_str() {
+        "esc" => Key::Esc,
+        "cr" | "enter" | "return" | "nl" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "space" => Key::Char(' '),
+        "lt" => Key::Char('<'),
+        "bs" | "backspace" => Key::Backspace,
+        "del" | "delete" => Key::Delete,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        "insert" => Key::Insert,
+        "s-tab" => Key::BackTab,
+        name if name.starts_with('f') => match name[1..].parse() {
+            Ok(n @ 1..=12) => Key::F(n),
+            _ => return None,
+        },
+        _ => {
+            let (modifier, c) = lower.split_once('-')?;
+            let mut chars = c.chars();
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            match modifier {
+                "c" => Key::Ctrl(c),
+                "a" | "m" => Key::Alt(c),
+                _ => return None,
+            }
+        }
+    };
+    Some(key)
+}
+
+// Parses a key as written in the key map files, e.g. `esc`, `ctrl-q`, `shift-a`
+// or `pagedown`
+pub fn from_name(name: &str) -> Option<Key> {
+    let mut rest = name;
+    let (mut ctrl, mut alt, mut shift) = (false, false, false);
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        if rest.chars().count() > 1 && lower.starts_with("ctrl-") {
+            ctrl = true;
+            rest = &rest[5..];
+        } else if rest.chars().count() > 1 && lower.starts_with("alt-") {
+            alt = true;
+            rest = &rest[4..];
+        } else if rest.chars().count() > 1 && lower.starts_with("shift-") {
+            shift = true;
+            rest = &rest[6..];
+        } else {
+            break;
+        }
+    }
+    let mut chars = rest.chars();
+    let key = match (chars.next(), chars.next()) {
+        (Some(c), None) => Key::Char(c),
+        _ => match rest.to_ascii_lowercase().as_str() {
+            "esc" => Key::Esc,
+            "enter" => Key::Char('\n'),
+            "tab" => Key::Char('\t'),
+            "backtab" => Key::BackTab,
+            "space" => Key::Char(' '),
+            "backspace" => Key::Backspace,
+            "del" | "delete" => Key::Delete,
+            "insert" => Key::Insert,
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "home" => Key::Home,
+            "end" => Key::End,
+            "pageup" => Key::PageUp,
+            "pagedown" => Key::PageDown,
+            name => match name.strip_prefix('f').map(str::parse::<u8>) {
+                Some(Ok(n @ 1..=12)) => Key::F(n),
+                _ => return None,
+            },
+        },
+    };
+    match key {
+        Key::Char('\t') if shift => Some(Key::BackTab),
+        Key::Char(c) if ctrl => Some(Key::Ctrl(c.to_ascii_lowercase())),
+        Key::Char(c) if alt => Some(Key::Alt(c)),
+        Key::Char(c) if shift => Some(Key::Char(c.to_ascii_uppercase())),
+        _ if ctrl || alt || shift => None,
+        key => Some(key),
+    }
+}
+
+// The name `from_name` reads back as `key`
+pub fn name(key: Key) -> String {
+    match key {
+        Key::Esc => "esc".to_string(),
+        Key::Char('\n') => "enter".to_string(),
+        Key::Char('\t') => "tab".to_string(),
+        Key::Char(' ') => "space".to_string(),
+        Key::Char(c) => c.to_string(),
+        Key::Ctrl(c) => format!("ctrl-{}", c),
+        Key::Alt(c) => format!("alt-{}", c),
+        Key::BackTab => "backtab".to_string(),
+        Key::Backspace => "backspace".to_string(),
+        Key::Delete => "del".to_string(),
+        Key::Insert => "insert".to_string(),
+        Key::Up => "up".to_string(),
+        Key::Down => "down".to_string(),
+        Key::Left => "left".to_string(),
+        Key::Right => "right".to_string(),
+        Key::Home => "home".to_string(),
+        Key::End => "end".to_string(),
+        Key::PageUp => "pageup".to_string(),
+        Key::PageDown => "pagedown".to_string(),
+        Key::F(n) => format!("f{}", n),
+    }
+}
+
+// A sequence as it is written in messages, e.g. `space w`
+pub fn names(keys: &[Key]) -> String {
+    let names: Vec<String> = keys.iter().map(|key| name(*key)).collect();
+    format!("`{}`", names.join(" "))
+}
+
+// Keys as `parse` reads them, e.g. `:w<CR>`
+pub fn notation(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|key| match key {
+            Key::Char('<') => "<lt>".to_string(),
+            Key::Char('\n') => "<CR>".to_string(),
+            Key::Char('\t') => "<Tab>".to_string(),
+            Key::Char(' ') => "<Space>".to_string(),
+            Key::Char(c) => c.to_string(),
+            Key::Ctrl(c) => format!("<C-{}>", c),
+            Key::Alt(c) => format!("<M-{}>", c),
+            Key::Esc => "<Esc>".to_string(),
+            Key::BackTab => "<S-Tab>".to_string(),
+            Key::Backspace => "<BS>".to_string(),
+            Key::Delete => "<Del>".to_string(),
+            Key::Insert => "<Insert>".to_string(),
+            Key::Up => "<Up>".to_string(),
+            Key::Down => "<Down>".to_string(),
+            Key::Left => "<Left>".to_string(),
+            Key::Right => "<Right>".to_string(),
+            Key::Home => "<Home>".to_string(),
+            Key::End => "<End>".to_string(),
+            Key::PageUp => "<PageUp>".to_string(),
+            Key::PageDown => "<PageDown>".to_string(),
+            Key::F(n) => format!("<F{}>", n),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_and_named_keys() {
+        let keys = parse("ix<Esc>:w<CR>");
+        assert_eq!(
+            keys,
+            [
+                Key::Char('i'),
+                Key::Char('x'),
+                Key::Esc,
+                Key::Char(':'),
+                Key::Char('w'),
+                Key::Char('\n'),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_modifiers_case_insensitively() {
+        assert_eq!(parse("<C-w>l"), [Key::Ctrl('w'), Key::Char('l')]);
+        assert_eq!(parse("<a-X><F12>"), [Key::Alt('x'), Key::F(12)]);
+    }
+
+    #[test]
+    fn takes_unknown_names_literally() {
+        assert_eq!(
+            parse("<foo>"),
+            "<foo>".chars().map(Key::Char).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            parse("<lt>a>"),
+            [Key::Char('<'), Key::Char('a'), Key::Char('>')]
+        );
+        assert_eq!(parse("<F13>").len(), 5);
+    }
+
+    #[test]
+    fn parses_leaders() {
+        let leaders = Leaders {
+            leader: Key::Char(','),
+            local: Key::Char(' '),
+        };
+        assert_eq!(
+            leaders.parse("<Leader>w<localleader>"),
+            [Key::Char(','), Key::Char('w'), Key::Char(' ')]
+        );
+        assert_eq!(parse("<leader>").len(), 8);
+    }
+}
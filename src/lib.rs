@@ -0,0 +1,39 @@
+mod action;
+mod backend;
+mod buffer;
+pub mod cli;
+pub mod config;
+mod diff;
+mod document;
+mod editor;
+mod encoding;
+mod error;
+mod fileformat;
+mod filetype;
+mod hex;
+mod highlight;
+mod job;
+mod keys;
+mod loader;
+mod mode;
+mod options;
+mod render;
+mod row;
+pub mod swap;
+mod terminal;
+mod window;
+mod writer;
+
+pub use action::{Action, KeyMapConfig, KeyMatch, Mapping};
+pub use backend::{Backend, Event, MemoryBackend, Waker};
+pub use document::Document;
+pub use editor::{Direction, Editor, Pos, RelativePos};
+pub use encoding::Encoding;
+pub use error::{Context, Error, Result};
+pub use fileformat::FileFormat;
+pub use filetype::FileType;
+pub use keys::Key;
+pub use mode::Mode;
+pub use render::{Cell, Color, CursorShape, Frame, Size};
+pub use row::Row;
+pub use terminal::Terminal;
@@ -0,0 +1,135 @@
+use crate::{Encoding, FileFormat, Row};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// Files at least this large are indexed in the background
+pub const LAZY_LOAD_THRESHOLD: u64 = 4 * 1024 * 1024;
+const FIRST_CHUNK_LINES: usize = 512;
+const CHUNK_LINES: usize = 16384;
+
+pub struct Chunk {
+    pub rows: Vec<Row>,
+    pub bytes: u64,
+    pub no_eol: bool,
+    // The first row read after bytes invalid in the detected encoding made the
+    // reader fall back to latin1 for the rest of the file
+    pub latin1_from: Option<usize>,
+    // The first row read after a bare line feed made the reader take a Dos
+    // file as Unix, like `FileFormat::detect` does for a whole file
+    pub unix_from: Option<usize>,
+}
+
+pub struct Loader {
+    receiver: Receiver<Result<Chunk, io::Error>>,
+    loaded: u64,
+    total: u64,
+}
+
+impl Loader {
+    pub fn spawn(
+        reader: BufReader<File>,
+        total: u64,
+        format: FileFormat,
+        encoding: Encoding,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = reader;
+            let mut format = format;
+            let mut encoding = encoding;
+            let mut chunk_lines = FIRST_CHUNK_LINES;
+            loop {
+                let chunk = read_chunk(&mut reader, chunk_lines, &mut format, &mut encoding);
+                let done = matches!(&chunk, Ok(c) if c.bytes == 0) || chunk.is_err();
+                if sender.send(chunk).is_err() || done {
+                    return;
+                }
+                chunk_lines = CHUNK_LINES;
+            }
+        });
+        Self {
+            receiver,
+            loaded: 0,
+            total,
+        }
+    }
+
+    /// Returns the next chunk if one is ready, or `None` while the reader is busy.
+    /// An empty chunk marks the end of the file.
+    pub fn try_next(&mut self) -> Option<Result<Chunk, io::Error>> {
+        match self.receiver.try_recv() {
+            Ok(chunk) => Some(self.account(chunk)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Ok(Chunk::empty())),
+        }
+    }
+
+    pub fn next(&mut self) -> Result<Chunk, io::Error> {
+        match self.receiver.recv() {
+            Ok(chunk) => self.account(chunk),
+            Err(_) => Ok(Chunk::empty()),
+        }
+    }
+
+    pub fn progress(&self) -> u8 {
+        if self.total == 0 {
+            return 100;
+        }
+        (self.loaded.saturating_mul(100) / self.total).min(100) as u8
+    }
+
+    fn account(&mut self, chunk: Result<Chunk, io::Error>) -> Result<Chunk, io::Error> {
+        if let Ok(chunk) = &chunk {
+            self.loaded += chunk.bytes;
+        }
+        chunk
+    }
+}
+
+impl Chunk {
+    fn empty() -> Self {
+        Self {
+            rows: Vec::new(),
+            bytes: 0,
+            no_eol: false,
+            latin1_from: None,
+            unix_from: None,
+        }
+    }
+}
+
+fn read_chunk(
+    reader: &mut BufReader<File>,
+    max_lines: usize,
+    format: &mut FileFormat,
+    encoding: &mut Encoding,
+) -> Result<Chunk, io::Error> {
+    let mut chunk = Chunk::empty();
+    let mut line = Vec::new();
+    while chunk.rows.len() < max_lines {
+        line.clear();
+        let bytes = reader.read_until(format.terminator(), &mut line)?;
+        if bytes == 0 {
+            break;
+        }
+        if *format == FileFormat::Dos && line.ends_with(b"\n") && !line.ends_with(b"\r\n") {
+            *format = FileFormat::Unix;
+            chunk.unix_from = Some(chunk.rows.len());
+        }
+        chunk.bytes += bytes as u64;
+        let line = match encoding.decode(&line) {
+            Ok(line) => line,
+            Err(_) => {
+                *encoding = Encoding::Latin1;
+                chunk.latin1_from = Some(chunk.rows.len());
+                Encoding::Latin1.decode(&line)?
+            }
+        };
+        let (text, terminated) = format.strip(&line);
+        chunk.no_eol |= !terminated;
+        chunk.rows.push(Row::from(text));
+    }
+    Ok(chunk)
+}
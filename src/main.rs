@@ -1,22 +1,122 @@
-#![allow(dead_code, unused_imports)]
-mod action;
-mod document;
-mod editor;
-mod filetype;
-mod highlight;
-mod row;
-mod mode;
-mod terminal;
+use rustvim::cli::{self, Invocation};
+use rustvim::{config, swap, Backend, Context, Editor, Terminal};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::{env, mem, process};
 
-pub use action::{Action, KeyMapConfig};
-pub use document::Document;
-pub use editor::{Direction, Pos, RelativePos};
-pub use filetype::FileType;
-pub use row::Row;
-pub use mode::Mode;
-pub use terminal::Terminal;
-use editor::Editor;
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
-    Editor::default().run();
+    // Help, version and usage errors are printed before the terminal is touched
+    let mut args = match cli::parse(env::args()) {
+        Ok(Invocation::Edit(args)) => args,
+        Ok(Invocation::CheckConfig(files)) => check_config(files),
+        Ok(Invocation::Help) => {
+            let _ = writeln!(io::stdout(), "{}", cli::USAGE);
+            return;
+        }
+        Ok(Invocation::Version) => {
+            let _ = writeln!(io::stdout(), "rustvim {}", VERSION);
+            return;
+        }
+        Err(message) => {
+            eprintln!("rustvim: {}\nMore info with: rustvim --help", message);
+            process::exit(1);
+        }
+    };
+    if args.recover && args.files.is_empty() {
+        list_swap_files();
+        return;
+    }
+    let commands = mem::take(&mut args.commands);
+    let (headless, stdin) = (args.headless, args.stdin);
+    let mut editor = Editor::new(args);
+    if headless {
+        // Without `-`, stdin is free to carry more Ex commands
+        let result = editor.run_headless(&commands, !stdin);
+        let _ = io::stdout().flush();
+        process::exit(result.map_or_else(|error| error.exit_code(), |()| 0));
+    }
+    // Keys are read from the terminal once the piped text has been read
+    if stdin {
+        if let Err(error) = Terminal::attach_tty() {
+            eprintln!("rustvim: could not open the terminal: {}", error);
+            process::exit(1);
+        }
+    }
+    let mut terminal = match Terminal::new().context("Could not initialize the terminal") {
+        Ok(terminal) => terminal,
+        Err(error) => {
+            eprintln!("rustvim: {}", error);
+            process::exit(error.exit_code());
+        }
+    };
+    editor.resize(terminal.size());
+    editor.run_commands(&commands);
+    if let Err(error) = editor.run(&mut terminal) {
+        // The message goes to the user's screen, not the editor's
+        drop(terminal);
+        eprintln!("rustvim: {}", error);
+        process::exit(error.exit_code());
+    }
+}
+
+fn list_swap_files() {
+    let swaps = swap::list();
+    if swaps.is_empty() {
+        println!("No swap files found.");
+    }
+    for path in swaps {
+        if let Ok(info) = swap::read(&path) {
+            let running = if info.is_running() {
+                ", still running"
+            } else {
+                ""
+            };
+            println!(
+                "{}  (file: {}, pid {}{})",
+                path.display(),
+                info.file_name.as_deref().unwrap_or("[No Name]"),
+                info.pid,
+                running
+            );
+        }
+    }
+}
+
+// Prints the problems in the config files like a compiler would, and exits with
+// an error status when there is an error among them
+fn check_config(mut files: Vec<PathBuf>) -> ! {
+    if files.is_empty() {
+        files = config::existing_files();
+    }
+    let diagnostics = config::check(&files);
+    for diagnostic in &diagnostics {
+        let severity = if diagnostic.warning {
+            "warning"
+        } else {
+            "error"
+        };
+        println!("{}: {}", severity, diagnostic.error);
+    }
+    let errors = diagnostics.iter().filter(|d| !d.warning).count();
+    let warnings = diagnostics.len() - errors;
+    let checked: Vec<String> = files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect();
+    if checked.is_empty() {
+        println!("No configuration files found");
+    } else {
+        println!(
+            "Checked {}: {} error(s), {} warning(s)",
+            checked.join(", "),
+            errors,
+            warnings
+        );
+    }
+    match diagnostics.iter().find(|d| !d.warning) {
+        Some(diagnostic) => process::exit(diagnostic.error.exit_code()),
+        None => process::exit(0),
+    }
 }
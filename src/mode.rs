@@ -1,4 +1,4 @@
-use crate::terminal::CursorShape;
+use crate::CursorShape;
 use serde::Deserialize;
 
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
@@ -16,9 +16,10 @@ impl Mode {
             Self::Insert => "Insert",
             Self::Visual => "Visual",
             Self::Command => "Command",
-        }.to_string()
+        }
+        .to_string()
     }
-    
+
     pub fn cursor_shape(self) -> CursorShape {
         match self {
             Self::Normal => CursorShape::SteadyBlock,
@@ -27,4 +28,4 @@ impl Mode {
             Self::Command => CursorShape::SteadyBar,
         }
     }
-}
\ No newline at end of file
+}
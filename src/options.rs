@@ -0,0 +1,402 @@
+use crate::keys::{self, Leaders};
+use crate::Color;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    Global,
+    // Each buffer and window may have its own value, falling back to the
+    // global one when it has none
+    Buffer,
+    Window,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Bool,
+    Number,
+    Text,
+    Color,
+    // A key name as in the key map, e.g. `space`
+    Key,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Number(usize),
+    Text(String),
+}
+
+impl Value {
+    pub fn is_on(&self) -> bool {
+        matches!(self, Self::Bool(true))
+    }
+
+    pub fn number(&self) -> usize {
+        match self {
+            Self::Number(value) => *value,
+            _ => 0,
+        }
+    }
+
+    pub fn color(&self) -> Option<Color> {
+        match self {
+            Self::Text(text) => Color::parse(text),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Number(value) => write!(f, "{}", value),
+            Self::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+pub struct OptionDef {
+    pub name: &'static str,
+    pub short: &'static str,
+    pub scope: Scope,
+    pub kind: Kind,
+    default: &'static str,
+}
+
+impl OptionDef {
+    pub fn default_value(&self) -> Value {
+        self.parse(self.default).expect("option defaults are valid")
+    }
+
+    // Reads a value as written after `:set name=`
+    pub fn parse(&self, text: &str) -> Result<Value, String> {
+        let invalid = || format!("Invalid argument: {}={}", self.name, text);
+        match self.kind {
+            Kind::Bool => match text {
+                "true" | "on" | "1" => Ok(Value::Bool(true)),
+                "false" | "off" | "0" => Ok(Value::Bool(false)),
+                _ => Err(invalid()),
+            },
+            Kind::Number => text
+                .parse()
+                .map_err(|_| invalid())
+                .and_then(|value| self.number(value)),
+            Kind::Text => Ok(Value::Text(text.to_string())),
+            Kind::Color => Color::parse(text)
+                .map(|_| Value::Text(text.to_string()))
+                .ok_or_else(invalid),
+            Kind::Key => keys::from_name(text)
+                .map(|_| Value::Text(text.to_string()))
+                .ok_or_else(invalid),
+        }
+    }
+
+    // Reads a value from a config file, where booleans and numbers are typed
+    pub fn parse_toml(&self, value: &toml::Value) -> Result<Value, String> {
+        match (self.kind, value) {
+            (Kind::Bool, toml::Value::Boolean(value)) => Ok(Value::Bool(*value)),
+            (Kind::Number, toml::Value::Integer(value)) => usize::try_from(*value)
+                .map_err(|_| format!("Invalid argument: {}={}", self.name, value))
+                .and_then(|value| self.number(value)),
+            (Kind::Text | Kind::Color | Kind::Key, toml::Value::String(value)) => self.parse(value),
+            (kind, _) => Err(format!(
+                "{} takes {}",
+                self.name,
+                match kind {
+                    Kind::Bool => "true or false",
+                    Kind::Number => "a number",
+                    Kind::Text => "a string",
+                    Kind::Color => "a color such as \"#efefef\"",
+                    Kind::Key => "a key such as \"space\"",
+                }
+            )),
+        }
+    }
+
+    // A tab stop of 0 would leave tabs nowhere to go
+    fn number(&self, value: usize) -> Result<Value, String> {
+        if self.name == "tabstop" && value == 0 {
+            return Err(format!(
+                "Argument must be positive: {}={}",
+                self.name, value
+            ));
+        }
+        Ok(Value::Number(value))
+    }
+
+    // How `:set name?` shows a value
+    pub fn show(&self, value: &Value) -> String {
+        match value {
+            Value::Bool(true) => self.name.to_string(),
+            Value::Bool(false) => format!("no{}", self.name),
+            value => format!("{}={}", self.name, value),
+        }
+    }
+}
+
+// Like Vim's options of the same names, apart from the colors
+pub const OPTIONS: &[OptionDef] = &[
+    OptionDef {
+        name: "autoread",
+        short: "ar",
+        scope: Scope::Global,
+        kind: Kind::Bool,
+        default: "false",
+    },
+    OptionDef {
+        name: "backup",
+        short: "bk",
+        scope: Scope::Global,
+        kind: Kind::Bool,
+        default: "false",
+    },
+    OptionDef {
+        name: "ignorecase",
+        short: "ic",
+        scope: Scope::Global,
+        kind: Kind::Bool,
+        default: "false",
+    },
+    OptionDef {
+        name: "smartcase",
+        short: "scs",
+        scope: Scope::Global,
+        kind: Kind::Bool,
+        default: "false",
+    },
+    OptionDef {
+        name: "tabstop",
+        short: "ts",
+        scope: Scope::Buffer,
+        kind: Kind::Number,
+        default: "4",
+    },
+    // 0 uses the tabstop
+    OptionDef {
+        name: "shiftwidth",
+        short: "sw",
+        scope: Scope::Buffer,
+        kind: Kind::Number,
+        default: "0",
+    },
+    OptionDef {
+        name: "expandtab",
+        short: "et",
+        scope: Scope::Buffer,
+        kind: Kind::Bool,
+        default: "false",
+    },
+    // These three are kept by the document itself
+    OptionDef {
+        name: "fileformat",
+        short: "ff",
+        scope: Scope::Buffer,
+        kind: Kind::Text,
+        default: "unix",
+    },
+    OptionDef {
+        name: "fileencoding",
+        short: "fenc",
+        scope: Scope::Buffer,
+        kind: Kind::Text,
+        default: "utf-8",
+    },
+    OptionDef {
+        name: "readonly",
+        short: "ro",
+        scope: Scope::Buffer,
+        kind: Kind::Bool,
+        default: "false",
+    },
+    OptionDef {
+        name: "number",
+        short: "nu",
+        scope: Scope::Window,
+        kind: Kind::Bool,
+        default: "false",
+    },
+    OptionDef {
+        name: "wrap",
+        short: "wrap",
+        scope: Scope::Window,
+        kind: Kind::Bool,
+        default: "false",
+    },
+    OptionDef {
+        name: "scrolloff",
+        short: "so",
+        scope: Scope::Window,
+        kind: Kind::Number,
+        default: "0",
+    },
+    // What <leader> and <localleader> stand for in mappings made after they
+    // are set
+    OptionDef {
+        name: "mapleader",
+        short: "mapleader",
+        scope: Scope::Global,
+        kind: Kind::Key,
+        default: "\\",
+    },
+    OptionDef {
+        name: "maplocalleader",
+        short: "maplocalleader",
+        scope: Scope::Global,
+        kind: Kind::Key,
+        default: "\\",
+    },
+    OptionDef {
+        name: "statusfg",
+        short: "statusfg",
+        scope: Scope::Global,
+        kind: Kind::Color,
+        default: "#3f3f3f",
+    },
+    OptionDef {
+        name: "statusbg",
+        short: "statusbg",
+        scope: Scope::Global,
+        kind: Kind::Color,
+        default: "#efefef",
+    },
+    // The status lines of the windows that are not active
+    OptionDef {
+        name: "statusncfg",
+        short: "statusncfg",
+        scope: Scope::Global,
+        kind: Kind::Color,
+        default: "#5f5f5f",
+    },
+    OptionDef {
+        name: "statusncbg",
+        short: "statusncbg",
+        scope: Scope::Global,
+        kind: Kind::Color,
+        default: "#bfbfbf",
+    },
+    OptionDef {
+        name: "linenrfg",
+        short: "linenrfg",
+        scope: Scope::Global,
+        kind: Kind::Color,
+        default: "#8f8f8f",
+    },
+    OptionDef {
+        name: "diffaddfg",
+        short: "diffaddfg",
+        scope: Scope::Global,
+        kind: Kind::Color,
+        default: "#859900",
+    },
+    OptionDef {
+        name: "diffdeletefg",
+        short: "diffdeletefg",
+        scope: Scope::Global,
+        kind: Kind::Color,
+        default: "#dc322f",
+    },
+];
+
+// Options whose values are kept by the document, such as the file format
+pub fn is_per_document(option: &OptionDef) -> bool {
+    ["fileformat", "fileencoding", "readonly"].contains(&option.name)
+}
+
+pub fn find(name: &str) -> Option<&'static OptionDef> {
+    OPTIONS
+        .iter()
+        .find(|option| option.name == name || option.short == name)
+}
+
+// The values set at one scope; options without one fall back to the next scope
+#[derive(Clone, Default)]
+pub struct Options {
+    values: HashMap<&'static str, Value>,
+}
+
+impl Options {
+    pub fn get(&self, option: &OptionDef) -> Option<&Value> {
+        self.values.get(option.name)
+    }
+
+    pub fn set(&mut self, option: &OptionDef, value: Value) {
+        self.values.insert(option.name, value);
+    }
+
+    // Takes every value set in `other` over the ones here
+    pub fn merge(&mut self, other: &Options) {
+        self.values.extend(
+            other
+                .values
+                .iter()
+                .map(|(name, value)| (*name, value.clone())),
+        );
+    }
+
+    // The leader keys set here, or else the defaults
+    pub fn leaders(&self) -> Leaders {
+        let key = |name| {
+            let option = find(name)?;
+            let value = self
+                .get(option)
+                .cloned()
+                .unwrap_or_else(|| option.default_value());
+            keys::from_name(&value.to_string())
+        };
+        let defaults = Leaders::default();
+        Leaders {
+            leader: key("mapleader").unwrap_or(defaults.leader),
+            local: key("maplocalleader").unwrap_or(defaults.local),
+        }
+    }
+}
+
+// One argument of `:set`
+pub enum Setting {
+    // `name`: turns a boolean on, shows any other option
+    Name,
+    // `noname`
+    Off,
+    // `invname` or `name!`
+    Toggle,
+    // `name?`
+    Show,
+    // `name&`
+    Reset,
+    // `name=value`
+    Assign(String),
+}
+
+// Splits an argument of `:set` into the option and what to do with it
+pub fn parse_setting(arg: &str) -> Result<(&'static OptionDef, Setting), String> {
+    let unknown = || format!("Unknown option: {}", arg);
+    if let Some((name, value)) = arg.split_once('=') {
+        let option = find(name).ok_or_else(unknown)?;
+        return Ok((option, Setting::Assign(value.to_string())));
+    }
+    for (suffix, setting) in [
+        ("?", Setting::Show),
+        ("&", Setting::Reset),
+        ("!", Setting::Toggle),
+    ] {
+        if let Some(name) = arg.strip_suffix(suffix) {
+            return Ok((find(name).ok_or_else(unknown)?, setting));
+        }
+    }
+    if let Some(option) = find(arg) {
+        return Ok((option, Setting::Name));
+    }
+    for (prefix, setting) in [("no", Setting::Off), ("inv", Setting::Toggle)] {
+        if let Some(option) = arg.strip_prefix(prefix).and_then(find) {
+            if option.kind != Kind::Bool {
+                return Err(format!("Invalid argument: {}", arg));
+            }
+            return Ok((option, setting));
+        }
+    }
+    Err(unknown())
+}
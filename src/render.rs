@@ -0,0 +1,171 @@
+use crate::Pos;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Color(pub u8, pub u8, pub u8);
+
+impl Color {
+    // Reads `#rrggbb`
+    pub fn parse(text: &str) -> Option<Self> {
+        let hex = text.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        Some(Self(channel(0)?, channel(2)?, channel(4)?))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CursorShape {
+    BlinkingBar,
+    BlinkingBlock,
+    BlinkingUnderline,
+    SteadyBar,
+    SteadyBlock,
+    SteadyUnderline,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Size {
+    pub width: u16,
+    pub height: u16,
+}
+
+// One screen cell; `None` colors are the terminal's defaults. A wide character
+// is followed by a cell with an empty symbol, which it covers on the screen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    pub symbol: String,
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            symbol: " ".to_string(),
+            fg: None,
+            bg: None,
+        }
+    }
+}
+
+impl Cell {
+    pub fn new(symbol: &str, fg: Option<Color>, bg: Option<Color>) -> Self {
+        Self {
+            symbol: symbol.to_string(),
+            fg,
+            bg,
+        }
+    }
+}
+
+// Everything the editor shows, independent of how it gets to the screen
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+    pub cursor: Option<Pos>,
+    pub cursor_shape: CursorShape,
+}
+
+impl Frame {
+    pub fn new(size: Size) -> Self {
+        let width = size.width as usize;
+        let height = size.height as usize;
+        Self {
+            width,
+            height,
+            cells: vec![Cell::default(); width * height],
+            cursor: None,
+            cursor_shape: CursorShape::SteadyBlock,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> Option<&Cell> {
+        (x < self.width && y < self.height).then(|| &self.cells[y * self.width + x])
+    }
+
+    pub fn row(&self, y: usize) -> &[Cell] {
+        let start = y.min(self.height) * self.width;
+        &self.cells[start..(start + self.width).min(self.cells.len())]
+    }
+
+    // The text shown on line `y`, without colors or trailing blanks
+    pub fn line(&self, y: usize) -> String {
+        let line: String = self
+            .row(y)
+            .iter()
+            .map(|cell| cell.symbol.as_str())
+            .collect();
+        line.trim_end().to_string()
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, cell: Cell) {
+        if x < self.width && y < self.height {
+            self.cells[y * self.width + x] = cell;
+        }
+    }
+
+    // Writes `text` one grapheme per cell, or two for wide ones, stopping after
+    // `width` cells or at the edge of the frame. Returns the number of cells written.
+    pub fn print(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        text: &str,
+        fg: Option<Color>,
+        bg: Option<Color>,
+    ) -> usize {
+        let width = width.min(self.width.saturating_sub(x));
+        let mut written = 0;
+        for grapheme in text.graphemes(true) {
+            let cells = grapheme.width().clamp(1, 2);
+            if written + cells > width {
+                break;
+            }
+            self.set(x + written, y, Cell::new(grapheme, fg, bg));
+            if cells == 2 {
+                self.set(x + written + 1, y, Cell::new("", fg, bg));
+            }
+            written += cells;
+        }
+        written
+    }
+
+    pub fn print_cells(&mut self, x: usize, y: usize, width: usize, cells: Vec<Cell>) -> usize {
+        let width = width.min(self.width.saturating_sub(x));
+        let mut written = 0;
+        for cell in cells.into_iter().take(width) {
+            self.set(x + written, y, cell);
+            written += 1;
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_wide_characters_in_two_cells() {
+        let mut frame = Frame::new(Size {
+            width: 8,
+            height: 1,
+        });
+        assert_eq!(frame.print(0, 0, 5, "日本語", None, None), 4);
+        assert_eq!(frame.line(0), "日本");
+        let symbols: Vec<&str> = frame.row(0).iter().map(|c| c.symbol.as_str()).collect();
+        assert_eq!(symbols[..5], ["日", "", "本", "", " "]);
+    }
+}
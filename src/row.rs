@@ -1,7 +1,6 @@
-use crate::{highlight, Direction};
-use std::cmp;
-use termion::color;
+use crate::{highlight, Cell, Direction};
 use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 type HlOpts = highlight::Options;
 type HlType = highlight::Type;
@@ -26,39 +25,57 @@ impl From<&str> for Row {
 }
 
 impl Row {
-    pub fn render(&self, start: usize, end: usize) -> String {
-        const TAB_SPACE: usize = 4;
-        let end = cmp::min(end, self.string.len());
-        let start = cmp::min(start, end);
-        let mut result = String::new();
-        let mut current_highlighting = &HlType::None;
-        for (index, grapheme) in self.string[..]
-            .graphemes(true)
-            .enumerate()
-            .skip(start)
-            .take(end - start)
-        {
-            if let Some(c) = grapheme.chars().next() {
-                let hl_type = self.highlighting.get(index).unwrap_or(&HlType::None);
-
-                if hl_type != current_highlighting {
-                    current_highlighting = hl_type;
-                    let start_highlight = format!("{}", color::Fg(hl_type.to_color()));
-                    result.push_str(&start_highlight[..]);
-                }
-
-                if c == '\t' {
-                    result.push_str(" ".repeat(TAB_SPACE).as_str());
-                } else {
-                    result.push(c);
+    // The cells from screen column `start` on, at most `width` of them. Tabs
+    // reach to the next multiple of `tabstop`.
+    pub fn render(&self, start: usize, width: usize, tabstop: usize) -> Vec<Cell> {
+        let end = start + width;
+        let mut result = Vec::new();
+        let mut x = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            if x >= end {
+                break;
+            }
+            let Some(c) = grapheme.chars().next() else {
+                continue;
+            };
+            let hl_type = self.highlighting.get(index).unwrap_or(&HlType::None);
+            let fg = (*hl_type != HlType::None).then(|| hl_type.to_color());
+            let width = cell_width(grapheme, x, tabstop);
+            let cells = if c == '\t' {
+                vec![Cell::new(" ", fg, None); width]
+            } else if c.is_ascii_control() {
+                let shown = ((c as u8 ^ 0x40) as char).to_string();
+                vec![Cell::new("^", fg, None), Cell::new(&shown, fg, None)]
+            } else if width == 1 {
+                vec![Cell::new(grapheme, fg, None)]
+            } else if x >= start && x + width <= end {
+                // The right half of a wide character is a cell without a symbol
+                vec![Cell::new(grapheme, fg, None), Cell::new("", fg, None)]
+            } else {
+                vec![Cell::new(" ", fg, None); width]
+            };
+            for cell in cells {
+                if x >= start && x < end {
+                    result.push(cell);
                 }
+                x += 1;
             }
         }
-        let end_highlight = format!("{}", color::Fg(color::Reset));
-        result.push_str(&end_highlight[..]);
         result
     }
 
+    // The screen column grapheme `index` starts at
+    pub fn display_x(&self, index: usize, tabstop: usize) -> usize {
+        self.string[..]
+            .graphemes(true)
+            .take(index)
+            .fold(0, |x, grapheme| x + cell_width(grapheme, x, tabstop))
+    }
+
+    pub fn display_width(&self, tabstop: usize) -> usize {
+        self.display_x(self.len, tabstop)
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -88,7 +105,13 @@ impl Row {
         self.string = result;
     }
 
-    pub fn find(&self, query: &str, at: usize, direction: Direction) -> Option<usize> {
+    pub fn find(
+        &self,
+        query: &str,
+        at: usize,
+        direction: Direction,
+        ignore_case: bool,
+    ) -> Option<usize> {
         if at > self.len || query.is_empty() {
             return None;
         }
@@ -103,24 +126,37 @@ impl Row {
             at
         };
 
-        let substring: String = self.string[..]
+        // Lowercased one grapheme at a time, so matches still start on one
+        let fold = |s: &str| {
+            if ignore_case {
+                s.to_lowercase()
+            } else {
+                s.to_string()
+            }
+        };
+        let graphemes: Vec<String> = self.string[..]
             .graphemes(true)
             .skip(start)
             .take(end - start)
+            .map(fold)
             .collect();
+        let substring = graphemes.concat();
+        let query = fold(query);
         let matching_byte_index = if direction == Direction::Forward {
-            substring.find(query)
+            substring.find(&query)
         } else {
-            substring.rfind(query)
-        };
-
-        if let Some(matching_byte_index) = matching_byte_index {
-            return substring[..]
-                .grapheme_indices(true)
-                .position(|(byte_index, _)| byte_index == matching_byte_index)
-                .map(|index| index + start);
-        }
-        None
+            substring.rfind(&query)
+        }?;
+
+        let mut byte_index = 0;
+        graphemes
+            .iter()
+            .position(|grapheme| {
+                let found = byte_index == matching_byte_index;
+                byte_index += grapheme.len();
+                found
+            })
+            .map(|index| index + start)
     }
 
     pub fn delete(&mut self, at: usize) {
@@ -173,6 +209,10 @@ impl Row {
         self.string.as_bytes()
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+
     pub fn unhighlight(&mut self) {
         self.highlighted = false;
     }
@@ -243,7 +283,7 @@ impl Row {
             return;
         }
         let mut index = 0;
-        while let Some(search_match) = self.find(word, index, Direction::Forward) {
+        while let Some(search_match) = self.find(word, index, Direction::Forward, false) {
             if let Some(next_index) = search_match.checked_add(word[..].graphemes(true).count()) {
                 for i in index.saturating_add(search_match)..next_index {
                     self.highlighting[i] = HlType::Match;
@@ -438,3 +478,41 @@ impl Row {
 fn is_seperator(c: char) -> bool {
     c.is_ascii_punctuation() || c.is_ascii_whitespace()
 }
+
+// The cells `grapheme` takes when it starts at screen column `x`
+fn cell_width(grapheme: &str, x: usize, tabstop: usize) -> usize {
+    match grapheme.chars().next() {
+        Some('\t') => tabstop.max(1) - x % tabstop.max(1),
+        Some(c) if c.is_ascii_control() => 2,
+        _ => grapheme.width().clamp(1, 2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols(row: &Row, start: usize, width: usize) -> Vec<String> {
+        row.render(start, width, 4)
+            .into_iter()
+            .map(|cell| cell.symbol)
+            .collect()
+    }
+
+    #[test]
+    fn renders_wide_characters_that_fit_whole() {
+        let row = Row::from("日本a");
+        assert_eq!(row.display_width(4), 5);
+        assert_eq!(symbols(&row, 0, 5), ["日", "", "本", "", "a"]);
+        // Halves cut off at either edge are shown as blanks
+        assert_eq!(symbols(&row, 1, 3), [" ", "本", ""]);
+        assert_eq!(symbols(&row, 0, 3), ["日", "", " "]);
+    }
+
+    #[test]
+    fn renders_tabs_and_control_characters() {
+        let row = Row::from("a\tb\x01");
+        assert_eq!(row.display_x(2, 4), 4);
+        assert_eq!(symbols(&row, 0, 8), ["a", " ", " ", " ", "b", "^", "A"]);
+    }
+}
@@ -0,0 +1,308 @@
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{config, writer};
+
+// Version 1 wrote rows one per line, which split rows that hold a line feed
+const HEADER: &str = "rustvim swap 2";
+const OLD_HEADER: &str = "rustvim swap 1";
+const SUFFIXES: [&str; 3] = ["swp", "swo", "swn"];
+// Like Vim's 'updatecount' and 'updatetime'
+const UPDATE_COUNT: usize = 200;
+const UPDATE_TIME: Duration = Duration::from_secs(4);
+
+pub struct SwapFile {
+    path: PathBuf,
+    written_changes: usize,
+    written_at: Option<Instant>,
+    // A failed write is retried after UPDATE_TIME rather than on every change
+    failed_at: Option<Instant>,
+}
+
+pub struct SwapInfo {
+    pub path: PathBuf,
+    pub pid: u32,
+    pub file_name: Option<String>,
+    pub rows: Vec<String>,
+}
+
+impl SwapFile {
+    // Picks the first free swap name so a second session never clobbers the first one's swap
+    pub fn for_file(file_name: Option<&str>) -> Self {
+        let candidates: Vec<PathBuf> = SUFFIXES
+            .iter()
+            .map(|suffix| swap_path(file_name, suffix))
+            .collect();
+        let path = candidates
+            .iter()
+            .find(|path| !path.exists())
+            .unwrap_or(&candidates[0])
+            .clone();
+        Self {
+            path,
+            written_changes: 0,
+            written_at: None,
+            failed_at: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_due(&self, changes: usize) -> bool {
+        self.due_at(changes)
+            .is_some_and(|time| time <= Instant::now())
+    }
+
+    // When the swap should be written if nothing else changes
+    pub fn due_at(&self, changes: usize) -> Option<Instant> {
+        if changes == self.written_changes {
+            return None;
+        }
+        let due = match self.written_at {
+            Some(time) if changes.abs_diff(self.written_changes) < UPDATE_COUNT => {
+                time + UPDATE_TIME
+            }
+            _ => Instant::now(),
+        };
+        Some(match self.failed_at {
+            Some(time) => due.max(time + UPDATE_TIME),
+            None => due,
+        })
+    }
+
+    pub fn write<'a, I>(
+        &mut self,
+        file_name: Option<&str>,
+        rows: I,
+        changes: usize,
+    ) -> Result<(), Error>
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let written = self.try_write(file_name, rows);
+        self.failed_at = written.is_err().then(Instant::now);
+        written?;
+        self.written_changes = changes;
+        self.written_at = Some(Instant::now());
+        Ok(())
+    }
+
+    fn try_write<'a, I>(&self, file_name: Option<&str>, rows: I) -> Result<(), Error>
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = format!(
+            "{}\npid {}\nfile {}\n\n",
+            HEADER,
+            process::id(),
+            file_name.and_then(absolute).unwrap_or_default()
+        );
+        // Each row is preceded by its length in bytes
+        for row in rows {
+            contents.push_str(&format!("{} {}\n", row.len(), row));
+        }
+        // Written beside the swap and renamed over it, so a failed write never
+        // leaves a cut off swap behind
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".new");
+        let temp = PathBuf::from(temp);
+        let written = create_private(&temp)
+            .and_then(|mut file| {
+                file.write_all(contents.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temp, &self.path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        written
+    }
+
+    pub fn remove(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        self.written_changes = 0;
+    }
+}
+
+// An existing swap left behind for `file_name` by a crashed or concurrent session
+pub fn find(file_name: &str) -> Option<PathBuf> {
+    SUFFIXES
+        .iter()
+        .map(|suffix| swap_path(Some(file_name), suffix))
+        .find(|path| path.exists())
+}
+
+pub fn read(path: &Path) -> Result<SwapInfo, Error> {
+    let contents = fs::read_to_string(path)?;
+    let (header, body) = contents
+        .split_once("\n\n")
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not a rustvim swap file"))?;
+    let mut lines = header.lines();
+    let rows = match lines.next() {
+        Some(HEADER) => read_rows(body)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "damaged rustvim swap file"))?,
+        Some(OLD_HEADER) => body
+            .strip_suffix('\n')
+            .map(|body| body.split('\n').map(String::from).collect())
+            .unwrap_or_default(),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "not a rustvim swap file",
+            ))
+        }
+    };
+    let mut info = SwapInfo {
+        path: path.to_path_buf(),
+        pid: 0,
+        file_name: None,
+        rows,
+    };
+    for line in lines {
+        match line.split_once(' ') {
+            Some(("pid", pid)) => info.pid = pid.parse().unwrap_or(0),
+            Some(("file", name)) if !name.is_empty() => info.file_name = Some(name.to_string()),
+            _ => (),
+        }
+    }
+    Ok(info)
+}
+
+// Reads rows written as their length, a space, the row and a line feed
+fn read_rows(mut body: &str) -> Option<Vec<String>> {
+    let mut rows = Vec::new();
+    while !body.is_empty() {
+        let (length, rest) = body.split_once(' ')?;
+        let length: usize = length.parse().ok()?;
+        rows.push(rest.get(..length)?.to_string());
+        body = rest.get(length..)?.strip_prefix('\n')?;
+    }
+    Some(rows)
+}
+
+pub fn is_swap_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| SUFFIXES.contains(&e))
+        .unwrap_or(false)
+}
+
+// Swap files in the current directory and the state directory
+pub fn list() -> Vec<PathBuf> {
+    let mut swaps = Vec::new();
+    for dir in [PathBuf::from("."), state_dir()] {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if is_swap_path(&path) && read(&path).is_ok() {
+                    swaps.push(path);
+                }
+            }
+        }
+    }
+    swaps.sort();
+    swaps
+}
+
+impl SwapInfo {
+    pub fn is_running(&self) -> bool {
+        self.pid != 0 && unsafe { libc::kill(self.pid as libc::pid_t, 0) } == 0
+    }
+}
+
+fn swap_path(file_name: Option<&str>, suffix: &str) -> PathBuf {
+    let Some(file_name) = file_name else {
+        return state_dir().join(format!("%noname.{}.{}", process::id(), suffix));
+    };
+    let path = Path::new(file_name);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if writer::is_writable(&dir) {
+        dir.join(format!(".{}.{}", name, suffix))
+    } else {
+        let full = absolute(file_name).unwrap_or_else(|| file_name.to_string());
+        state_dir().join(format!("{}.{}", full.replace('/', "%"), suffix))
+    }
+}
+
+// Writes `contents` to a new file next to the swap directory, for what a crashed
+// session could not save. Returns the file's path.
+pub fn write_recovery(contents: &str) -> Result<PathBuf, Error> {
+    let dir = config::state_dir();
+    fs::create_dir_all(&dir)?;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let path = dir.join(format!("recovery-{}-{}.txt", time, process::id()));
+    create_private(&path)?.write_all(contents.as_bytes())?;
+    Ok(path)
+}
+
+// Swap and recovery files hold the buffer's text, so only the owner may read
+// them. The mode is set again in case the file was already there.
+fn create_private(path: &Path) -> Result<fs::File, Error> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+fn state_dir() -> PathBuf {
+    config::state_dir().join("swap")
+}
+
+fn absolute(file_name: &str) -> Option<String> {
+    let path = Path::new(file_name);
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().ok()?.join(path)
+    };
+    Some(path.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_rows_with_line_feeds() {
+        let path = env::temp_dir().join(format!("rustvim-{}-swap.swp", process::id()));
+        let mut swap = SwapFile {
+            path: path.clone(),
+            written_changes: 0,
+            written_at: None,
+            failed_at: None,
+        };
+        fs::write(&path, "left behind").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let rows = ["one\ntwo", "", "three\r"];
+        swap.write(Some("file.txt"), rows.into_iter(), 1).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let info = read(&path).unwrap();
+        swap.remove();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(info.pid, process::id());
+        assert_eq!(info.rows, rows);
+    }
+}
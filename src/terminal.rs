@@ -1,100 +1,446 @@
-use crate::Pos;
+use crate::{Backend, Color, CursorShape, Event, Frame, Key, Pos, Size, Waker};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
 use std::io::{self, stdin, stdout, Write};
-use termion::{self, color, event::Key, input::TermRead, raw::IntoRawMode};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+use std::{mem, panic, ptr};
+use termion::event;
+use termion::{self, color};
+use unicode_width::UnicodeWidthStr;
 
-pub struct Size {
-    pub width: u16,
-    pub height: u16,
+const ENABLE_FOCUS_REPORTING: &str = "\x1b[?1004h";
+const DISABLE_FOCUS_REPORTING: &str = "\x1b[?1004l";
+const FOCUS_IN: &[u8] = b"\x1b[I";
+// The shape the user's terminal is configured with
+const DEFAULT_CURSOR_SHAPE: &str = "\x1b[0 q";
+
+// Whether the terminal is in the editor's state, and the mode to put it back in.
+// These are static so the panic hook can restore the terminal before the
+// panic message is printed.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static COOKED_MODE: Mutex<Option<libc::termios>> = Mutex::new(None);
+static PANIC_HOOK: Once = Once::new();
+
+// The write end of the pipe SIGWINCH is reported through
+static RESIZE_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_resize(_: libc::c_int) {
+    let fd = RESIZE_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let saved = errno::errno();
+        unsafe {
+            libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1);
+        }
+        errno::set_errno(saved);
+    }
 }
 
 pub struct Terminal {
     size: Size,
-    _stdout: termion::raw::RawTerminal<io::Stdout>,
-}
-
-pub enum CursorShape {
-    BlinkingBar,
-    BlinkingBlock,
-    BlinkingUnderline,
-    SteadyBar,
-    SteadyBlock,
-    SteadyUnderline,
+    cursor_shape: Option<CursorShape>,
+    // What the screen shows, to send only what changed
+    screen: Option<Frame>,
+    // Readable after the terminal was resized
+    resize_pipe: RawFd,
+    // Readable after a waker was woken; the write end is shared with the wakers
+    wake_pipe: (OwnedFd, Arc<OwnedFd>),
+    // Bytes read from stdin that have not been made into events yet
+    input: VecDeque<u8>,
 }
 
 impl Terminal {
     pub fn new() -> Result<Self, std::io::Error> {
         let size = termion::terminal_size()?;
+        let wake_pipe = Terminal::pipe()?;
+        let resize_pipe = Terminal::watch_resize()?;
+        Terminal::enter_raw_mode()?;
+        PANIC_HOOK.call_once(|| {
+            let hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                Terminal::restore();
+                hook(info);
+            }));
+        });
+        print!(
+            "{}{}",
+            termion::screen::ToAlternateScreen,
+            ENABLE_FOCUS_REPORTING
+        );
         Ok(Self {
             size: Size {
                 width: size.0,
-                height: size.1 - 2,
+                height: size.1,
             },
-            _stdout: stdout().into_raw_mode()?,
+            cursor_shape: None,
+            screen: None,
+            resize_pipe,
+            wake_pipe: (wake_pipe.0, Arc::new(wake_pipe.1)),
+            input: VecDeque::new(),
         })
     }
 
-    pub fn size(&self) -> &Size {
-        &self.size
+    fn enter_raw_mode() -> Result<(), io::Error> {
+        let mut mode: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDOUT_FILENO, &mut mode) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let cooked = mode;
+        unsafe { libc::cfmakeraw(&mut mode) };
+        if unsafe { libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, &mode) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        *COOKED_MODE.lock().unwrap_or_else(|e| e.into_inner()) = Some(cooked);
+        ACTIVE.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Gives the user back the terminal as it was: cooked mode, the default
+    // cursor and colors, and the main screen with its scrollback. Does nothing
+    // when it was restored already.
+    fn restore() {
+        if !ACTIVE.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        // Written directly, as a panic may have happened with stdout locked
+        let codes = format!(
+            "{}{}{}{}{}",
+            Terminal::color_codes(None, None),
+            DEFAULT_CURSOR_SHAPE,
+            termion::cursor::Show,
+            DISABLE_FOCUS_REPORTING,
+            termion::screen::ToMainScreen
+        );
+        unsafe {
+            libc::write(
+                libc::STDOUT_FILENO,
+                codes.as_ptr() as *const libc::c_void,
+                codes.len(),
+            );
+        }
+        if let Some(mode) = *COOKED_MODE.lock().unwrap_or_else(|e| e.into_inner()) {
+            unsafe { libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, &mode) };
+        }
     }
 
-    pub fn clear_screen() {
-        print!("{}", termion::clear::All);
+    fn color_codes(fg: Option<Color>, bg: Option<Color>) -> String {
+        let fg = match fg {
+            Some(Color(r, g, b)) => color::Fg(color::Rgb(r, g, b)).to_string(),
+            None => color::Fg(color::Reset).to_string(),
+        };
+        let bg = match bg {
+            Some(Color(r, g, b)) => color::Bg(color::Rgb(r, g, b)).to_string(),
+            None => color::Bg(color::Reset).to_string(),
+        };
+        fg + &bg
     }
 
     pub fn flush() -> Result<(), std::io::Error> {
         io::stdout().flush()
     }
 
-    pub fn read_key() -> Result<Key, io::Error> {
-        loop {
-            if let Some(key) = stdin().lock().keys().next() {
-                return key;
+    // Points stdin at the controlling terminal once piped input has been read,
+    // so keys can still be read
+    pub fn attach_tty() -> Result<(), io::Error> {
+        let tty = File::open("/dev/tty")?;
+        if unsafe { libc::dup2(tty.as_raw_fd(), libc::STDIN_FILENO) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // A non-blocking pipe, as its read and write ends
+    fn pipe() -> Result<(OwnedFd, OwnedFd), io::Error> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let ends = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        for fd in fds {
+            // pipe2 would do this in one call, but it is not available everywhere
+            let failed = unsafe {
+                libc::fcntl(
+                    fd,
+                    libc::F_SETFL,
+                    libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK,
+                ) == -1
+                    || libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1
+            };
+            if failed {
+                return Err(io::Error::last_os_error());
             }
         }
+        Ok(ends)
     }
 
-    pub fn clear_current_line() {
-        print!("{}", termion::clear::CurrentLine);
+    fn drain(fd: RawFd) {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
     }
 
-    pub fn cursor_goto(pos: &Pos) {
-        let x = (pos.x + 1) as u16;
-        let y = (pos.y + 1) as u16;
-        print!("{}", termion::cursor::Goto(x, y));
+    // Makes SIGWINCH readable from the returned pipe, so that waiting for keys
+    // can be woken up by it without a race
+    fn watch_resize() -> Result<RawFd, io::Error> {
+        let (read, write) = Terminal::pipe()?;
+        RESIZE_PIPE.store(write.into_raw_fd(), Ordering::Relaxed);
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = on_resize as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        if unsafe { libc::sigaction(libc::SIGWINCH, &action, ptr::null_mut()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(read.into_raw_fd())
     }
 
-    pub fn cursor_hide() {
-        print!("{}", termion::cursor::Hide);
+    // Returns the new size if the resize changed it
+    fn take_resize(&mut self) -> Result<Option<Size>, io::Error> {
+        Terminal::drain(self.resize_pipe);
+        let (width, height) = termion::terminal_size()?;
+        let size = Size { width, height };
+        if size == self.size {
+            return Ok(None);
+        }
+        self.size = size;
+        Ok(Some(size))
     }
 
-    pub fn cursor_show() {
-        print!("{}", termion::cursor::Show);
+    // Reads what is available on stdin, bypassing std's buffer which poll cannot
+    // see into. Returns false at the end of the input.
+    fn read_input(&mut self) -> Result<bool, io::Error> {
+        let mut buf = [0u8; 4096];
+        let read = unsafe {
+            libc::read(
+                libc::STDIN_FILENO,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        match read {
+            -1 => {
+                let error = io::Error::last_os_error();
+                match error.kind() {
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => Ok(true),
+                    _ => Err(error),
+                }
+            }
+            0 => Ok(false),
+            read => {
+                self.input.extend(&buf[..read as usize]);
+                Ok(true)
+            }
+        }
     }
 
-    pub fn set_bg_color(color: color::Rgb) {
-        print!("{}", color::Bg(color));
+    // Makes the next event out of the bytes read so far. An escape that ends
+    // the input is the <Esc> key, not the start of a sequence.
+    fn parse_input(&mut self) -> Option<Event> {
+        while let Some(byte) = self.input.pop_front() {
+            let parsed = if byte == 0x1b && self.input.is_empty() {
+                Ok(event::Event::Key(event::Key::Esc))
+            } else if byte == 0x1b && self.input.iter().take(2).eq(&FOCUS_IN[1..]) {
+                // termion does not know this sequence
+                self.input.drain(..2);
+                return Some(Event::FocusGained);
+            } else {
+                let mut consumed = 0;
+                let mut rest = self.input.iter().map(|byte| {
+                    consumed += 1;
+                    Ok(*byte)
+                });
+                let parsed = event::parse_event(byte, &mut rest);
+                self.input.drain(..consumed);
+                parsed
+            };
+            if let Some(key) = parsed.ok().and_then(Terminal::convert_key) {
+                return Some(Event::Key(key));
+            }
+        }
+        None
     }
 
-    pub fn reset_bg_color() {
-        print!("{}", color::Bg(color::Reset));
+    // The keys the editor has a counterpart for, the others are dropped
+    fn convert_key(event: event::Event) -> Option<Key> {
+        let event::Event::Key(key) = event else {
+            return None;
+        };
+        let key = match key {
+            event::Key::Char(c) => Key::Char(c),
+            event::Key::Ctrl(c) => Key::Ctrl(c),
+            event::Key::Alt(c) => Key::Alt(c),
+            event::Key::F(n) => Key::F(n),
+            event::Key::Esc => Key::Esc,
+            event::Key::Backspace => Key::Backspace,
+            event::Key::Delete => Key::Delete,
+            event::Key::Insert => Key::Insert,
+            event::Key::BackTab => Key::BackTab,
+            event::Key::Up => Key::Up,
+            event::Key::Down => Key::Down,
+            event::Key::Left => Key::Left,
+            event::Key::Right => Key::Right,
+            event::Key::Home => Key::Home,
+            event::Key::End => Key::End,
+            event::Key::PageUp => Key::PageUp,
+            event::Key::PageDown => Key::PageDown,
+            _ => return None,
+        };
+        Some(key)
     }
 
-    pub fn set_fg_color(color: color::Rgb) {
-        print!("{}", color::Fg(color));
+    fn cursor_shape_code(shape: CursorShape) -> String {
+        match shape {
+            CursorShape::BlinkingBar => termion::cursor::BlinkingBar.to_string(),
+            CursorShape::BlinkingBlock => termion::cursor::BlinkingBlock.to_string(),
+            CursorShape::BlinkingUnderline => termion::cursor::BlinkingUnderline.to_string(),
+            CursorShape::SteadyBar => termion::cursor::SteadyBar.to_string(),
+            CursorShape::SteadyBlock => termion::cursor::SteadyBlock.to_string(),
+            CursorShape::SteadyUnderline => termion::cursor::SteadyUnderline.to_string(),
+        }
     }
+}
 
-    pub fn reset_fg_color() {
-        print!("{}", color::Fg(color::Reset));
+impl Backend for Terminal {
+    fn size(&self) -> Size {
+        self.size
     }
-    
-    pub fn set_cursor_shape(shape: CursorShape) {
-        match shape {
-            CursorShape::BlinkingBar => print!("{}", termion::cursor::BlinkingBar),
-            CursorShape::BlinkingBlock => print!("{}", termion::cursor::BlinkingBlock),
-            CursorShape::BlinkingUnderline => print!("{}", termion::cursor::BlinkingUnderline),
-            CursorShape::SteadyBar => print!("{}", termion::cursor::SteadyBar),
-            CursorShape::SteadyBlock => print!("{}", termion::cursor::SteadyBlock),
-            CursorShape::SteadyUnderline => print!("{}", termion::cursor::SteadyUnderline),
+
+    // Sends only the cells that differ from the last frame, repainting
+    // everything when there is none or the size changed
+    fn draw(&mut self, frame: &Frame) -> Result<(), io::Error> {
+        let mut out = String::new();
+        let previous = match self.screen.take() {
+            Some(screen)
+                if screen.width() == frame.width() && screen.height() == frame.height() =>
+            {
+                screen
+            }
+            _ => {
+                out.push_str(&Terminal::color_codes(None, None));
+                let _ = write!(out, "{}", termion::clear::All);
+                Frame::new(Size {
+                    width: frame.width() as u16,
+                    height: frame.height() as u16,
+                })
+            }
+        };
+        // Where the terminal's cursor is and which colors it writes with, when known
+        let mut position = None;
+        let mut colors = None;
+        for y in 0..frame.height() {
+            for (x, cell) in frame.row(y).iter().enumerate() {
+                // The right half of a wide character is drawn along with it
+                if previous.cell(x, y) == Some(cell) || cell.symbol.is_empty() {
+                    continue;
+                }
+                if colors.is_none() {
+                    let _ = write!(out, "{}", termion::cursor::Hide);
+                }
+                if position != Some(Pos { x, y }) {
+                    let _ = write!(out, "{}", termion::cursor::Goto(x as u16 + 1, y as u16 + 1));
+                }
+                if colors != Some((cell.fg, cell.bg)) {
+                    colors = Some((cell.fg, cell.bg));
+                    out.push_str(&Terminal::color_codes(cell.fg, cell.bg));
+                }
+                out.push_str(&cell.symbol);
+                // After the last column the position depends on the terminal's wrapping,
+                // and after a wide character on how wide the terminal thinks it is
+                position = (x + 1 < frame.width() && cell.symbol.width() == 1)
+                    .then_some(Pos { x: x + 1, y });
+            }
+        }
+        if colors.is_some() {
+            out.push_str(&Terminal::color_codes(None, None));
+        }
+        if self.cursor_shape != Some(frame.cursor_shape) {
+            self.cursor_shape = Some(frame.cursor_shape);
+            out.push_str(&Terminal::cursor_shape_code(frame.cursor_shape));
+        }
+        let changed = colors.is_some() || previous.cursor != frame.cursor;
+        match frame.cursor {
+            Some(cursor) if changed => {
+                let (x, y) = (cursor.x as u16 + 1, cursor.y as u16 + 1);
+                let _ = write!(out, "{}", termion::cursor::Goto(x, y));
+                let _ = write!(out, "{}", termion::cursor::Show);
+            }
+            None if changed => {
+                let _ = write!(out, "{}", termion::cursor::Hide);
+            }
+            _ => (),
+        }
+        self.screen = Some(frame.clone());
+        let mut stdout = stdout();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+
+    fn read_event(&mut self, timeout: Option<Duration>) -> Result<Option<Event>, io::Error> {
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+        });
+        if let Some(event) = self.parse_input() {
+            return Ok(Some(event));
+        }
+        loop {
+            let fds = [
+                stdin().as_raw_fd(),
+                self.resize_pipe,
+                self.wake_pipe.0.as_raw_fd(),
+            ];
+            let mut fds = fds.map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } {
+                -1 => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(error);
+                    }
+                }
+                0 => return Ok(None),
+                _ => (),
+            }
+            if fds[1].revents != 0 {
+                if let Some(size) = self.take_resize()? {
+                    return Ok(Some(Event::Resize(size)));
+                }
+            }
+            if fds[0].revents != 0 {
+                let open = self.read_input()?;
+                if let Some(event) = self.parse_input() {
+                    return Ok(Some(event));
+                }
+                if !open {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+            }
+            if fds[2].revents != 0 {
+                Terminal::drain(self.wake_pipe.0.as_raw_fd());
+                return Ok(None);
+            }
+        }
+    }
+
+    fn waker(&self) -> Waker {
+        let pipe = Arc::clone(&self.wake_pipe.1);
+        Waker::new(move || unsafe {
+            libc::write(pipe.as_raw_fd(), [0u8].as_ptr() as *const libc::c_void, 1);
+        })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::signal(libc::SIGWINCH, libc::SIG_DFL);
+            libc::close(self.resize_pipe);
+            libc::close(RESIZE_PIPE.swap(-1, Ordering::Relaxed));
         }
+        let _ = Terminal::flush();
+        Terminal::restore();
     }
 }
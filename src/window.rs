@@ -0,0 +1,434 @@
+use crate::options::Options;
+use crate::Pos;
+
+// The smallest window is one text line plus its status line
+const MIN_HEIGHT: usize = 2;
+const MIN_WIDTH: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Split {
+    // Windows stacked above each other, as created by `:split`
+    Horizontal,
+    // Windows side by side with a separator column, as created by `:vsplit`
+    Vertical,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    fn extent(&self, split: Split) -> usize {
+        match split {
+            Split::Horizontal => self.height,
+            Split::Vertical => self.width,
+        }
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+pub struct Window {
+    pub id: usize,
+    pub buffer_id: usize,
+    pub cursor_pos: Pos,
+    pub offset: Pos,
+    // Values set with :setlocal, e.g. number
+    pub options: Options,
+}
+
+impl Window {
+    pub fn new(id: usize, buffer_id: usize) -> Self {
+        Self {
+            id,
+            buffer_id,
+            cursor_pos: Pos::default(),
+            offset: Pos::default(),
+            options: Options::default(),
+        }
+    }
+}
+
+// The windows of a tab page that is not being shown
+pub struct TabPage {
+    pub windows: Vec<Window>,
+    pub layout: Layout,
+    pub active: usize,
+}
+
+impl TabPage {
+    pub fn new(window: Window) -> Self {
+        Self {
+            layout: Layout::Window(window.id),
+            windows: vec![window],
+            active: 0,
+        }
+    }
+}
+
+// A tree of splits whose leaves are window ids. `sizes` are the extents of the
+// children in cells; they are scaled when the available area changes.
+pub enum Layout {
+    Window(usize),
+    Split {
+        split: Split,
+        children: Vec<Layout>,
+        sizes: Vec<usize>,
+    },
+}
+
+impl Layout {
+    // The area of every window, in drawing order
+    pub fn rects(&self, area: Rect) -> Vec<(usize, Rect)> {
+        let mut rects = Vec::new();
+        self.collect_rects(area, &mut rects);
+        rects
+    }
+
+    fn collect_rects(&self, area: Rect, rects: &mut Vec<(usize, Rect)>) {
+        match self {
+            Self::Window(id) => rects.push((*id, area)),
+            Self::Split {
+                split,
+                children,
+                sizes,
+            } => {
+                for (child, rect) in children.iter().zip(child_rects(*split, sizes, area)) {
+                    child.collect_rects(rect, rects);
+                }
+            }
+        }
+    }
+
+    // The separator columns between side by side windows
+    pub fn separators(&self, area: Rect) -> Vec<Rect> {
+        let mut separators = Vec::new();
+        self.collect_separators(area, &mut separators);
+        separators
+    }
+
+    fn collect_separators(&self, area: Rect, separators: &mut Vec<Rect>) {
+        if let Self::Split {
+            split,
+            children,
+            sizes,
+        } = self
+        {
+            let rects = child_rects(*split, sizes, area);
+            for (index, (child, rect)) in children.iter().zip(&rects).enumerate() {
+                if *split == Split::Vertical && index + 1 < rects.len() {
+                    separators.push(Rect {
+                        x: rect.x + rect.width,
+                        y: rect.y,
+                        width: 1,
+                        height: rect.height,
+                    });
+                }
+                child.collect_separators(*rect, separators);
+            }
+        }
+    }
+
+    // Places window `new` before `target`, giving it half of the target's area.
+    // Returns false when the target is too small to be split.
+    pub fn split(&mut self, target: usize, new: usize, split: Split, area: Rect) -> bool {
+        self.fix_sizes(area);
+        let Some(rect) = self.rect_of(target, area) else {
+            return false;
+        };
+        let separator = usize::from(split == Split::Vertical);
+        let available = rect.extent(split).saturating_sub(separator);
+        let min = min_size(split);
+        if available < 2 * min {
+            return false;
+        }
+        let first = available / 2;
+        self.insert(target, new, split, [first, available - first])
+    }
+
+    fn insert(&mut self, target: usize, new: usize, split: Split, halves: [usize; 2]) -> bool {
+        match self {
+            Self::Window(id) if *id == target => {
+                *self = Self::Split {
+                    split,
+                    children: vec![Self::Window(new), Self::Window(target)],
+                    sizes: halves.to_vec(),
+                };
+                true
+            }
+            Self::Window(_) => false,
+            Self::Split {
+                split: own_split,
+                children,
+                sizes,
+            } => {
+                let position = children
+                    .iter()
+                    .position(|child| matches!(child, Self::Window(id) if *id == target));
+                if let (Some(index), true) = (position, *own_split == split) {
+                    children.insert(index, Self::Window(new));
+                    sizes[index] = halves[1];
+                    sizes.insert(index, halves[0]);
+                    return true;
+                }
+                children
+                    .iter_mut()
+                    .any(|child| child.insert(target, new, split, halves))
+            }
+        }
+    }
+
+    // Removes window `target`; its space goes to the neighbouring window
+    pub fn remove(&mut self, target: usize, area: Rect) -> bool {
+        self.fix_sizes(area);
+        self.remove_in(target)
+    }
+
+    fn remove_in(&mut self, target: usize) -> bool {
+        let Self::Split {
+            split,
+            children,
+            sizes,
+        } = self
+        else {
+            return false;
+        };
+        let position = children
+            .iter()
+            .position(|child| matches!(child, Self::Window(id) if *id == target));
+        let removed = match position {
+            Some(index) => {
+                children.remove(index);
+                let size = sizes.remove(index) + usize::from(*split == Split::Vertical);
+                let neighbour = index.min(sizes.len() - 1);
+                sizes[neighbour] += size;
+                true
+            }
+            None => children.iter_mut().any(|child| child.remove_in(target)),
+        };
+        if children.len() == 1 {
+            *self = children.remove(0);
+        }
+        removed
+    }
+
+    pub fn window_ids(&self) -> Vec<usize> {
+        self.rects(Rect::default())
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    pub fn rect_of(&self, target: usize, area: Rect) -> Option<Rect> {
+        self.rects(area)
+            .into_iter()
+            .find(|(id, _)| *id == target)
+            .map(|(_, rect)| rect)
+    }
+
+    // Gives every window in each split the same share of the space
+    pub fn equalize(&mut self) {
+        if let Self::Split {
+            children, sizes, ..
+        } = self
+        {
+            sizes.fill(1);
+            for child in children {
+                child.equalize();
+            }
+        }
+    }
+
+    // Sets the extent of `target` along `split`, taking the space from or
+    // giving it to the next window in the closest split of that kind
+    pub fn resize(&mut self, target: usize, split: Split, size: usize, area: Rect) {
+        self.fix_sizes(area);
+        self.resize_in(target, split, size);
+    }
+
+    // None when `target` is not in this subtree, otherwise whether it was resized
+    fn resize_in(&mut self, target: usize, split: Split, size: usize) -> Option<bool> {
+        match self {
+            Self::Window(id) => (*id == target).then_some(false),
+            Self::Split {
+                split: own_split,
+                children,
+                sizes,
+            } => {
+                let (index, resized) =
+                    children.iter_mut().enumerate().find_map(|(index, child)| {
+                        child
+                            .resize_in(target, split, size)
+                            .map(|resized| (index, resized))
+                    })?;
+                if resized || *own_split != split {
+                    return Some(resized);
+                }
+                let neighbour = if index + 1 < sizes.len() {
+                    index + 1
+                } else {
+                    index - 1
+                };
+                let min = min_size(split);
+                let total = sizes[index] + sizes[neighbour];
+                let size = size.clamp(min.min(total), total.saturating_sub(min).max(min));
+                sizes[index] = size.min(total);
+                sizes[neighbour] = total - sizes[index];
+                Some(true)
+            }
+        }
+    }
+
+    // Rewrites the sizes to the extents the windows have in `area`
+    fn fix_sizes(&mut self, area: Rect) {
+        if let Self::Split {
+            split,
+            children,
+            sizes,
+        } = self
+        {
+            let rects = child_rects(*split, sizes, area);
+            for ((child, size), rect) in children.iter_mut().zip(sizes.iter_mut()).zip(rects) {
+                *size = rect.extent(*split);
+                child.fix_sizes(rect);
+            }
+        }
+    }
+}
+
+fn min_size(split: Split) -> usize {
+    match split {
+        Split::Horizontal => MIN_HEIGHT,
+        Split::Vertical => MIN_WIDTH,
+    }
+}
+
+// Shares the extent of `area` out in proportion to `sizes`
+fn child_rects(split: Split, sizes: &[usize], area: Rect) -> Vec<Rect> {
+    let separators = match split {
+        Split::Horizontal => 0,
+        Split::Vertical => sizes.len().saturating_sub(1),
+    };
+    let available = area.extent(split).saturating_sub(separators);
+    let total: usize = sizes.iter().sum::<usize>().max(1);
+    let mut rects = Vec::new();
+    let mut start = 0;
+    let mut used = 0;
+    for (index, size) in sizes.iter().enumerate() {
+        let extent = if index + 1 == sizes.len() {
+            available - used
+        } else {
+            (size * available / total).min(available - used)
+        };
+        rects.push(match split {
+            Split::Horizontal => Rect {
+                y: area.y + start,
+                height: extent,
+                ..area
+            },
+            Split::Vertical => Rect {
+                x: area.x + start,
+                width: extent,
+                ..area
+            },
+        });
+        used += extent;
+        start += extent + usize::from(split == Split::Vertical);
+    }
+    rects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: 81,
+        height: 24,
+    };
+
+    fn widths(layout: &Layout) -> Vec<(usize, usize)> {
+        layout
+            .rects(AREA)
+            .into_iter()
+            .map(|(id, rect)| (id, rect.width))
+            .collect()
+    }
+
+    #[test]
+    fn splits_a_window_in_half() {
+        let mut layout = Layout::Window(0);
+        assert!(layout.split(0, 1, Split::Vertical, AREA));
+        assert_eq!(widths(&layout), [(1, 40), (0, 40)]);
+        assert_eq!(
+            layout.separators(AREA),
+            [Rect {
+                x: 40,
+                y: 0,
+                width: 1,
+                height: 24
+            }]
+        );
+
+        assert!(layout.split(0, 2, Split::Horizontal, AREA));
+        assert_eq!(layout.window_ids(), [1, 2, 0]);
+        assert_eq!(
+            layout.rect_of(0, AREA),
+            Some(Rect {
+                x: 41,
+                y: 12,
+                width: 40,
+                height: 12
+            })
+        );
+    }
+
+    #[test]
+    fn joins_splits_of_the_same_kind() {
+        let mut layout = Layout::Window(0);
+        layout.split(0, 1, Split::Vertical, AREA);
+        layout.split(0, 2, Split::Vertical, AREA);
+        assert!(matches!(&layout, Layout::Split { children, .. } if children.len() == 3));
+        assert_eq!(widths(&layout), [(1, 40), (2, 19), (0, 20)]);
+    }
+
+    #[test]
+    fn refuses_to_split_a_small_window() {
+        let mut layout = Layout::Window(0);
+        let area = Rect { height: 3, ..AREA };
+        assert!(!layout.split(0, 1, Split::Horizontal, area));
+        assert_eq!(layout.window_ids(), [0]);
+    }
+
+    #[test]
+    fn gives_the_space_of_a_removed_window_to_its_neighbour() {
+        let mut layout = Layout::Window(0);
+        layout.split(0, 1, Split::Vertical, AREA);
+        layout.split(0, 2, Split::Vertical, AREA);
+        assert!(layout.remove(2, AREA));
+        assert_eq!(widths(&layout), [(1, 40), (0, 40)]);
+        assert!(layout.remove(1, AREA));
+        assert!(matches!(layout, Layout::Window(0)));
+        assert!(!layout.remove(0, AREA));
+    }
+
+    #[test]
+    fn resizes_and_equalizes() {
+        let mut layout = Layout::Window(0);
+        layout.split(0, 1, Split::Vertical, AREA);
+        layout.resize(1, Split::Vertical, 10, AREA);
+        assert_eq!(widths(&layout), [(1, 10), (0, 70)]);
+        layout.resize(1, Split::Vertical, 100, AREA);
+        assert_eq!(widths(&layout), [(1, 79), (0, 1)]);
+        layout.equalize();
+        assert_eq!(widths(&layout), [(1, 40), (0, 40)]);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::config;
+
+const DEFAULT_MODE: u32 = 0o666;
+
+// Writes `contents` to a temporary file next to the target and renames it into
+// place, so a crash or a full disk never leaves a half-written file behind.
+pub fn write_atomic(file_name: &str, contents: &[u8], backup: bool) -> Result<(), Error> {
+    let target = resolve(Path::new(file_name));
+    let metadata = fs::metadata(&target).ok();
+    let temp = temp_path(&target);
+    let mode = metadata
+        .as_ref()
+        .map(|m| m.permissions().mode())
+        .unwrap_or(DEFAULT_MODE);
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp)
+    {
+        Ok(file) => file,
+        // The directory is not writable, but the file itself may be
+        Err(error) if error.kind() == ErrorKind::PermissionDenied && metadata.is_some() => {
+            return write_in_place(&target, contents, backup);
+        }
+        Err(error) => return Err(describe(error)),
+    };
+    // Made once the directory is known to be writable, else the in-place write
+    // keeps its own copy
+    let copied = match metadata {
+        Some(_) if backup => fs::copy(&target, backup_path(&target)).map(|_| ()),
+        _ => Ok(()),
+    };
+
+    let result = copied
+        .and_then(|()| finish(&mut file, contents, metadata.as_ref()))
+        .and_then(|_| fs::rename(&temp, &target));
+    if let Err(error) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(describe(error));
+    }
+    if let Some(dir) = target
+        .parent()
+        .and_then(|dir| File::open(dir_or_cwd(dir)).ok())
+    {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+// Whether the current user may write to `path`, honouring ACLs and root
+pub fn is_writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+fn finish(file: &mut File, contents: &[u8], metadata: Option<&fs::Metadata>) -> Result<(), Error> {
+    file.write_all(contents)?;
+    file.sync_all()?;
+    if let Some(metadata) = metadata {
+        // Only root can give files away, so a failed chown is not fatal. It
+        // comes first since it clears the setuid and setgid bits.
+        unsafe {
+            libc::fchown(file.as_raw_fd(), metadata.uid(), metadata.gid());
+        }
+        // The umask may have narrowed the mode given to open
+        file.set_permissions(metadata.permissions())?;
+    }
+    Ok(())
+}
+
+// Overwrites the file itself, which a failure partway through would leave cut
+// off, so it is first copied to the state directory. The copy is kept if
+// `backup` is set, since it cannot go next to the file.
+fn write_in_place(target: &Path, contents: &[u8], backup: bool) -> Result<(), Error> {
+    let copy = in_place_backup_path(target);
+    if let Some(dir) = copy.parent() {
+        fs::create_dir_all(dir).map_err(describe)?;
+    }
+    fs::copy(target, &copy).map_err(describe)?;
+    // The copy holds what may be private text, whatever the file's own mode
+    fs::set_permissions(&copy, fs::Permissions::from_mode(0o600)).map_err(describe)?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(target)
+        .map_err(describe)?;
+    let result = file
+        .set_len(0)
+        .and_then(|()| file.write_all(contents))
+        .and_then(|()| file.sync_all());
+    if let Err(error) = result {
+        let message = match error.raw_os_error() {
+            Some(libc::ENOSPC) => "no space left on device".to_string(),
+            _ => error.to_string(),
+        };
+        return Err(Error::new(
+            error.kind(),
+            format!(
+                "{}, the file may be cut off but its old contents are in {}",
+                message,
+                copy.display()
+            ),
+        ));
+    }
+    if !backup {
+        let _ = fs::remove_file(&copy);
+    }
+    Ok(())
+}
+
+// Follows symlinks so the link itself is kept and the real file is replaced
+fn resolve(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn backup_path(target: &Path) -> PathBuf {
+    let mut name = target.as_os_str().to_owned();
+    name.push("~");
+    PathBuf::from(name)
+}
+
+fn in_place_backup_path(target: &Path) -> PathBuf {
+    let name = target.to_string_lossy().replace('/', "%");
+    config::state_dir()
+        .join("backup")
+        .join(format!("{}~", name))
+}
+
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp_name = format!(".{}.{}.tmp", name, process::id());
+    match target.parent() {
+        Some(dir) => dir.join(temp_name),
+        None => PathBuf::from(temp_name),
+    }
+}
+
+fn dir_or_cwd(dir: &Path) -> &Path {
+    if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    }
+}
+
+fn describe(error: Error) -> Error {
+    if error.raw_os_error() == Some(libc::ENOSPC) {
+        Error::new(
+            error.kind(),
+            "no space left on device, the file on disk was left unchanged",
+        )
+    } else {
+        error
+    }
+}
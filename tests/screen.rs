@@ -0,0 +1,184 @@
+use rustvim::{cli::Args, Backend, Editor, Event, MemoryBackend, Size};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Once;
+use std::time::Duration;
+
+static STATE_DIR: Once = Once::new();
+
+fn start(width: u16, height: u16) -> (Editor, MemoryBackend) {
+    // Keeps the swap files of the unnamed buffers out of the user's own state
+    // directory. Every test comes through here before it starts an editor.
+    STATE_DIR.call_once(|| {
+        let dir = std::env::temp_dir().join("rustvim-tests-state");
+        std::env::set_var("XDG_STATE_HOME", dir);
+    });
+    let backend = MemoryBackend::new(Size { width, height });
+    let mut editor = Editor::new(Args {
+        clean: true,
+        ..Args::default()
+    });
+    editor.resize(backend.size());
+    (editor, backend)
+}
+
+fn run(editor: &mut Editor, backend: &mut MemoryBackend, keys: &str) -> Vec<String> {
+    backend.push_keys(keys);
+    editor.run(backend).unwrap();
+    backend.lines()
+}
+
+// Quitting removes the swap files the unnamed buffers got
+fn quit(editor: &mut Editor, backend: &mut MemoryBackend) {
+    run(editor, backend, ":qa!<CR>");
+    assert!(editor.should_quit());
+}
+
+fn temp_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rustvim-{}-{}", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn insert_and_write() {
+    let path = temp_file("write.txt");
+    let (mut editor, mut backend) = start(50, 6);
+    let keys = format!("ihello<CR>world<Esc>:w {}<CR>", path.display());
+    let mut lines = run(&mut editor, &mut backend, &keys);
+    // The status line shows the start of the file name, which holds the process id
+    let status = lines.remove(4);
+    assert!(status.starts_with("[Normal] "), "{}", status);
+    assert!(status.ends_with(" - 2 lines plain | ut"), "{}", status);
+    assert_eq!(
+        lines,
+        ["hello", "world", "~", "~", "File saved successfully"]
+    );
+    assert_eq!(fs::read_to_string(&path).unwrap(), "hello\nworld\n");
+    fs::remove_file(&path).unwrap();
+    quit(&mut editor, &mut backend);
+}
+
+#[test]
+fn split_windows() {
+    let (mut editor, mut backend) = start(40, 10);
+    let lines = run(&mut editor, &mut backend, "ione<CR>two<Esc>:sp<CR>:vs<CR>");
+    assert_eq!(
+        lines,
+        [
+            "one                |one",
+            "two                |two",
+            "~                  |~",
+            "[Normal] [No Name] |[No Name] - 2 lines",
+            "one",
+            "two",
+            "~",
+            "~",
+            "[No Name] - 2 lines (modified) plain | u",
+            "",
+        ]
+    );
+    quit(&mut editor, &mut backend);
+}
+
+#[test]
+fn tab_line() {
+    let (mut editor, mut backend) = start(40, 6);
+    let lines = run(&mut editor, &mut backend, "ione<Esc>:tabnew<CR>");
+    assert_eq!(
+        lines,
+        [
+            " 1 [No Name] +  2 [No Name]",
+            "~",
+            "~     RVim editor -- version 0.1.0",
+            "~",
+            "[Normal] [No Name] - 0 lines plain | utf",
+            "",
+        ]
+    );
+    let lines = run(&mut editor, &mut backend, "gt");
+    assert_eq!(lines[0], " 1 [No Name] +  2 [No Name]");
+    assert_eq!(lines[1], "one");
+    quit(&mut editor, &mut backend);
+}
+
+#[test]
+fn shell_job_output() {
+    let (mut editor, mut backend) = start(40, 6);
+    run(&mut editor, &mut backend, ":!echo from job<CR>");
+    // The job's output arrives from another thread, which wakes the backend for
+    // the editor to read it
+    while !backend.lines().iter().any(|line| line.contains("done")) {
+        assert!(
+            backend.wait_for_wake(Duration::from_secs(5)),
+            "the job never finished"
+        );
+        editor.run(&mut backend).unwrap();
+    }
+    assert_eq!(
+        backend.lines(),
+        [
+            ":!echo from job",
+            "from job",
+            "",
+            "!echo from job: done",
+            "1-4 of 4 lines (q to close)",
+            "",
+        ]
+    );
+    let lines = run(&mut editor, &mut backend, "q");
+    assert_eq!(lines[4], "[Normal] [No Name] - 0 lines plain | utf");
+    quit(&mut editor, &mut backend);
+}
+
+#[test]
+fn wide_characters() {
+    let (mut editor, mut backend) = start(12, 4);
+    let lines = run(&mut editor, &mut backend, "i日本語a<Esc>");
+    assert_eq!(lines[0], "日本語a");
+    let screen = backend.screen();
+    assert_eq!(screen.cell(1, 0).map(|cell| cell.symbol.as_str()), Some(""));
+    assert_eq!(
+        screen.cell(6, 0).map(|cell| cell.symbol.as_str()),
+        Some("a")
+    );
+    assert_eq!(screen.cursor.map(|cursor| cursor.x), Some(7));
+    let lines = run(&mut editor, &mut backend, "i<CR>e\u{301}x<Esc>");
+    assert_eq!(lines[1], "e\u{301}x");
+    assert_eq!(backend.screen().cursor.map(|cursor| cursor.x), Some(2));
+    quit(&mut editor, &mut backend);
+}
+
+#[test]
+fn normal_bang_skips_mappings() {
+    let (mut editor, mut backend) = start(20, 6);
+    run(&mut editor, &mut backend, "ione<CR>two<Esc>k:map k j<CR>");
+    let lines = run(&mut editor, &mut backend, ":normal k<CR>iX<Esc>");
+    assert_eq!(lines[..2], ["one", "twoX"]);
+    let lines = run(&mut editor, &mut backend, ":normal! k<CR>iY<Esc>");
+    assert_eq!(lines[..2], ["oneY", "twoX"]);
+    quit(&mut editor, &mut backend);
+}
+
+#[test]
+fn focus_checks_hidden_buffers() {
+    let hidden = temp_file("hidden.txt");
+    let shown = temp_file("shown.txt");
+    fs::write(&hidden, "old\n").unwrap();
+    fs::write(&shown, "shown\n").unwrap();
+    let (mut editor, mut backend) = start(60, 4);
+    let keys = format!(
+        ":e {}<CR>:e {}<CR>:set autoread<CR>",
+        hidden.display(),
+        shown.display()
+    );
+    run(&mut editor, &mut backend, &keys);
+    fs::write(&hidden, "new text\n").unwrap();
+    backend.push_event(Event::FocusGained);
+    editor.run(&mut backend).unwrap();
+    let lines = run(&mut editor, &mut backend, ":bp<CR>");
+    assert_eq!(lines[0], "new text");
+    fs::remove_file(&hidden).unwrap();
+    fs::remove_file(&shown).unwrap();
+    quit(&mut editor, &mut backend);
+}